@@ -1,129 +1,576 @@
-use bitcoin::{Block, block::Header};
+use bitcoin::{Block, BlockHash, Transaction, block::Header};
+use std::fmt;
 use std::fs::File;
 use std::io::{BufReader, Read, Seek, SeekFrom};
 use std::path::Path;
 use anyhow::{Result, anyhow};
+use memmap2::Mmap;
+use crate::index::{BlockLocation, BlockSummary};
+
+// What went wrong reading the block an index entry points at
+#[derive(Debug)]
+pub enum BlockReadErrorKind {
+    Unreadable(String),                 // I/O, magic or decoding failure
+    Missing,                            // no block at the offset (end of file or padding)
+    SizeMismatch { found: u32, expected: u32 }, // the block's frame disagrees with the indexed size
+    HashMismatch { found: BlockHash },  // a different block than the index expects
+    MerkleRootMismatch,                 // header commits to different transactions
+    WitnessCommitmentMismatch,          // coinbase commits to different witness data
+}
+
+// A block read through the index did not match its entry
+#[derive(Debug)]
+pub struct BlockReadError {
+    pub height: u32,
+    pub file_path: String,
+    pub file_offset: u64,
+    pub expected_hash: BlockHash,
+    pub kind: BlockReadErrorKind,
+}
+
+impl fmt::Display for BlockReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Block at height {} ({} offset {}): ", self.height, self.file_path, self.file_offset)?;
+        match &self.kind {
+            BlockReadErrorKind::Unreadable(reason) => write!(f, "could not be read: {}", reason)?,
+            BlockReadErrorKind::Missing => write!(f, "no block found at this offset")?,
+            BlockReadErrorKind::SizeMismatch { found, expected } => write!(f, "block size {} differs from indexed size {}", found, expected)?,
+            BlockReadErrorKind::HashMismatch { found } => write!(f, "found block {} instead of {}", found, self.expected_hash)?,
+            BlockReadErrorKind::MerkleRootMismatch => write!(f, "merkle root does not match the transactions")?,
+            BlockReadErrorKind::WitnessCommitmentMismatch => write!(f, "witness commitment does not match the transactions")?,
+        }
+        write!(f, ". Run verify-index to check the index against the block files.")
+    }
+}
+
+impl std::error::Error for BlockReadError {}
+
+// Consensus limit on a block's serialized size; anything claiming more is corrupt
+const MAX_BLOCK_SIZE: usize = 4_000_000;
+
+// How much of a block file find_magic reads at a time
+const MAGIC_SEARCH_CHUNK_SIZE: u64 = 1 << 20;
+
+// A stretch of a block file a recovering scan could not parse and skipped over
+#[derive(Debug, Clone)]
+pub struct SkippedRange {
+    pub file_path: String,
+    pub start: u64,
+    pub end: u64,
+    pub reason: String,
+}
+
+// XOR bytes read from the given offset of a block file with the repeating 8-byte key,
+// a whole word at a time
+fn deobfuscate(data: &mut [u8], offset: u64, xor_key: &[u8; 8]) {
+    if *xor_key == [0; 8] {
+        return;
+    }
+
+    // Rotate the key so its first byte lines up with the start of data
+    let mut key = *xor_key;
+    key.rotate_left((offset % 8) as usize);
+    let key_word = u64::from_ne_bytes(key);
+
+    let mut chunks = data.chunks_exact_mut(8);
+    for chunk in &mut chunks {
+        let word = u64::from_ne_bytes(chunk.try_into().unwrap()) ^ key_word;
+        chunk.copy_from_slice(&word.to_ne_bytes());
+    }
+    for (byte, key_byte) in chunks.into_remainder().iter_mut().zip(key) {
+        *byte ^= key_byte;
+    }
+}
+
+// Check the magic and size that precede every block in a blk file, given the 8 raw bytes
+// read at offset. Returns the block's size, or None for zero padding (the end of valid blocks).
+fn parse_block_frame(mut magic_and_size: [u8; 8], offset: u64, xor_key: &[u8; 8]) -> Result<Option<usize>> {
+    // Check for padding (all zeros) before deobfuscation
+    if magic_and_size == [0; 8] {
+        return Ok(None);
+    }
+    deobfuscate(&mut magic_and_size, offset, xor_key);
+
+    // Check magic bytes for mainnet (0xf9beb4d9)
+    let magic_bytes = &magic_and_size[0..4];
+    if magic_bytes != [0xf9, 0xbe, 0xb4, 0xd9] {
+        return Err(anyhow!("Invalid magic bytes at offset {}: {:02x?}", offset, magic_bytes));
+    }
+
+    let block_size = u32::from_le_bytes(magic_and_size[4..8].try_into().unwrap()) as usize;
+    if block_size < 80 {
+        return Err(anyhow!("Block at offset {} claims {} bytes, too small for a header", offset, block_size));
+    }
+    if block_size > MAX_BLOCK_SIZE {
+        return Err(anyhow!("Block at offset {} claims {} bytes, more than a block can hold", offset, block_size));
+    }
+
+    Ok(Some(block_size))
+}
 
 pub struct BlockFileReader {
     reader: BufReader<File>,
     file_path: String,
+    file_len: u64,
     xor_key: [u8; 8],
 }
 
 impl BlockFileReader {
     pub fn new_with_xor_key<P: AsRef<Path>>(path: P, xor_key: [u8; 8]) -> Result<Self> {
         let file = File::open(&path)?;
+        let file_len = file.metadata()?.len();
         let reader = BufReader::new(file);
         let file_path = path.as_ref().to_string_lossy().to_string();
 
         Ok(BlockFileReader {
             reader,
             file_path,
+            file_len,
             xor_key,
         })
     }
 
     fn deobfuscate_data(&self, data: &mut [u8], offset: u64) {
-        for (i, byte) in data.iter_mut().enumerate() {
-            *byte ^= self.xor_key[(offset as usize + i) % 8];
-        }
+        deobfuscate(data, offset, &self.xor_key);
     }
 
-    pub fn read_next_block(&mut self) -> Result<Option<(Block, u64)>> {
+    // Read the magic and size at the current position, returning the block's offset and size
+    // with the reader left at the start of its data, or None at the end of the file or padding
+    fn read_block_frame(&mut self) -> Result<Option<(u64, usize)>> {
         let current_offset = self.reader.stream_position()?;
 
         // Read magic bytes + size (8 bytes total)
-        let mut header_bytes = [0u8; 8];
-        match self.reader.read_exact(&mut header_bytes) {
+        let mut magic_and_size = [0u8; 8];
+        match self.reader.read_exact(&mut magic_and_size) {
             Ok(_) => {},
             Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
             Err(e) => return Err(e.into()),
         }
 
-        // Deobfuscate the header
-        self.deobfuscate_data(&mut header_bytes, current_offset);
+        let block_size = parse_block_frame(magic_and_size, current_offset, &self.xor_key)?;
+        Ok(block_size.map(|block_size| (current_offset, block_size)))
+    }
+
+    // Read and deobfuscate the data of the block whose frame starts at block_offset
+    fn read_block_data(&mut self, block_offset: u64, block_size: usize) -> Result<Vec<u8>> {
+        let mut data = vec![0u8; block_size];
+        self.reader.read_exact(&mut data).map_err(|e| match e.kind() {
+            std::io::ErrorKind::UnexpectedEof => anyhow!("Block at offset {} claims {} bytes, but the file ends first", block_offset, block_size),
+            _ => e.into(),
+        })?;
+        self.deobfuscate_data(&mut data, block_offset + 8);
+        Ok(data)
+    }
+
+    // Read the next block's header and walk its transactions (without deserializing them)
+    // to count them and measure the block's weight
+    pub fn read_next_summary(&mut self) -> Result<Option<(BlockSummary, u64, u32)>> {
+        let Some((current_offset, block_size)) = self.read_block_frame()? else {
+            return Ok(None);
+        };
+        let block_data = self.read_block_data(current_offset, block_size)?;
+
+        let header: Header = bitcoin::consensus::deserialize(&block_data[..80])?;
+        let (tx_count, witness_bytes) = scan_transactions(&block_data[80..])
+            .map_err(|e| anyhow!("Malformed block at offset {}: {}", current_offset, e))?;
 
-        // Extract magic bytes and size
-        let magic_bytes = &header_bytes[0..4];
-        let size_bytes = &header_bytes[4..8];
+        // Everything except witness data (including the segwit marker and flag) counts four times
+        let weight = 4 * block_size as u64 - 3 * witness_bytes;
 
-        // Check magic bytes for mainnet (0xf9beb4d9)
-        if magic_bytes != [0xf9, 0xbe, 0xb4, 0xd9] {
-            return Err(anyhow!("Invalid magic bytes at offset {}: {:02x?}", current_offset, magic_bytes));
+        let summary = BlockSummary {
+            header,
+            tx_count,
+            weight: weight as u32,
+        };
+        Ok(Some((summary, current_offset, block_size as u32)))
+    }
+
+    // Like read_next_summary, but instead of failing on unparseable data (or stopping at zero
+    // padding) search forward for the next magic that starts a readable block, recording the
+    // bytes passed over in skipped. Zero padding that runs to the end of the file is not recorded.
+    pub fn read_next_summary_recovering(&mut self, skipped: &mut Vec<SkippedRange>) -> Result<Option<(BlockSummary, u64, u32)>> {
+        let file_len = self.file_len;
+        let start = self.reader.stream_position()?;
+
+        let (reason, padding) = match self.read_next_summary() {
+            Ok(Some(found)) => return Ok(Some(found)),
+            Ok(None) if start + 8 > file_len => return Ok(None),
+            Ok(None) => ("zero padding".to_string(), true),
+            Err(e) => (e.to_string(), false),
+        };
+
+        let mut search_from = start + 1;
+        while let Some(candidate) = self.find_magic(search_from)? {
+            self.reader.seek(SeekFrom::Start(candidate))?;
+            if let Ok(Some(found)) = self.read_next_summary() {
+                skipped.push(SkippedRange {
+                    file_path: self.file_path.clone(),
+                    start,
+                    end: candidate,
+                    reason,
+                });
+                return Ok(Some(found));
+            }
+            search_from = candidate + 1;
         }
 
-        let block_size = u32::from_le_bytes([size_bytes[0], size_bytes[1], size_bytes[2], size_bytes[3]]) as usize;
+        if !padding {
+            skipped.push(SkippedRange {
+                file_path: self.file_path.clone(),
+                start,
+                end: file_len,
+                reason,
+            });
+        }
+        self.reader.seek(SeekFrom::Start(file_len))?;
+        Ok(None)
+    }
+
+    // Offset of the first magic bytes at or after from, reading the file a chunk at a time
+    fn find_magic(&mut self, from: u64) -> Result<Option<u64>> {
+        let file_len = self.file_len;
+        let mut chunk = Vec::new();
+        let mut position = from;
 
+        while position + 4 <= file_len {
+            let length = MAGIC_SEARCH_CHUNK_SIZE.min(file_len - position) as usize;
+            chunk.resize(length, 0);
+            self.reader.seek(SeekFrom::Start(position))?;
+            self.reader.read_exact(&mut chunk)?;
+            self.deobfuscate_data(&mut chunk, position);
 
-        // Read block data
-        let mut block_data = vec![0u8; block_size];
-        self.reader.read_exact(&mut block_data)?;
+            // Check magic bytes for mainnet (0xf9beb4d9)
+            if let Some(index) = chunk.windows(4).position(|window| window == [0xf9, 0xbe, 0xb4, 0xd9]) {
+                return Ok(Some(position + index as u64));
+            }
 
-        // Deobfuscate the block data
-        self.deobfuscate_data(&mut block_data, current_offset + 8);
+            // Overlap chunks by three bytes so magic split across a boundary is still found
+            position += (length as u64 - 3).max(1);
+        }
+
+        Ok(None)
+    }
+
+    // Read the single transaction starting tx_offset bytes into the block stored at block_offset
+    pub fn read_transaction(&mut self, block_offset: u64, block_size: u32, tx_offset: u32) -> Result<Transaction> {
+        if tx_offset >= block_size {
+            return Err(anyhow!("Transaction offset {} is past the end of the {}-byte block at offset {}",
+                               tx_offset, block_size, block_offset));
+        }
 
-        // Parse block using bitcoin crate
-        let block: Block = bitcoin::consensus::deserialize(&block_data)?;
+        // Transactions are not length-prefixed, so read up to the end of the block
+        let data_offset = block_offset + 8 + tx_offset as u64;
+        self.reader.seek(SeekFrom::Start(data_offset))?;
+        let mut data = vec![0u8; (block_size - tx_offset) as usize];
+        self.reader.read_exact(&mut data)?;
+        self.deobfuscate_data(&mut data, data_offset);
 
-        Ok(Some((block, current_offset)))
+        let (transaction, _consumed) = bitcoin::consensus::deserialize_partial(&data)?;
+        Ok(transaction)
     }
 
-    pub fn read_next_header(&mut self) -> Result<Option<(Header, u64, u32)>> {
-        // Block file format: [4 bytes magic][4 bytes size][80 bytes header][variable transactions]
-        let current_offset = self.reader.stream_position()?;
+}
 
-        // Read magic bytes + size (8 bytes total)
-        let mut magic_and_size = [0u8; 8];
-        match self.reader.read_exact(&mut magic_and_size) {
-            Ok(_) => {},
-            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
-            Err(e) => return Err(e.into()),
+// How many block file mappings MappedBlockReader keeps. Reads through the index are nearly
+// sequential, so a few recent files cover blocks stored slightly out of order.
+const MAX_MAPPED_FILES: usize = 8;
+
+// Reads the blocks an index points at by memory-mapping block files and keeping the most
+// recently used mappings for later heights. Only the requested block is copied out and
+// deobfuscated, into a buffer reused from one block to the next.
+pub struct MappedBlockReader {
+    mappings: Vec<(String, Mmap)>, // least recently used first
+    buffer: Vec<u8>,
+    xor_key: [u8; 8],
+}
+
+impl MappedBlockReader {
+    pub fn new(xor_key: [u8; 8]) -> Self {
+        MappedBlockReader {
+            mappings: Vec::with_capacity(MAX_MAPPED_FILES),
+            buffer: Vec::new(),
+            xor_key,
         }
+    }
 
-        // Check if we hit padding (all zeros) before deobfuscation
-        if magic_and_size == [0; 8] {
-            return Ok(None); // End of valid blocks, continue normally
+    // Make a block file's mapping the most recently used, mapping it (and evicting the least
+    // recently used) if needed
+    fn use_mapping(&mut self, file_path: &str) -> Result<()> {
+        match self.mappings.iter().position(|(path, _)| path == file_path) {
+            Some(position) => {
+                let entry = self.mappings.remove(position);
+                self.mappings.push(entry);
+            }
+            None => {
+                let file = File::open(file_path)?;
+                // SAFETY: we only read byte ranges the index points at, which hold blocks Bitcoin
+                // Core has already written. Core appends after them and truncates the preallocated
+                // tail when it finalizes a file, neither of which changes those bytes, and pruning
+                // deletes whole files, which leaves an existing mapping valid.
+                let mmap = unsafe { Mmap::map(&file)? };
+                if self.mappings.len() == MAX_MAPPED_FILES {
+                    self.mappings.remove(0);
+                }
+                self.mappings.push((file_path.to_string(), mmap));
+            }
         }
+        Ok(())
+    }
 
-        // Deobfuscate the magic and size
-        self.deobfuscate_data(&mut magic_and_size, current_offset);
+    // Copy up to limit bytes of the block stored at offset into the buffer and deobfuscate them.
+    // Returns the block's full size, or None at zero padding or the end of the file.
+    fn load_block(&mut self, file_path: &str, offset: u64, limit: usize) -> Result<Option<usize>> {
+        self.use_mapping(file_path)?;
+        let mmap = &self.mappings.last().unwrap().1;
 
-        // Extract magic bytes and size
-        let magic_bytes = &magic_and_size[0..4];
-        let size_bytes = &magic_and_size[4..8];
+        let start = offset as usize;
+        if start + 8 > mmap.len() {
+            return Ok(None);
+        }
 
-        // Check magic bytes for mainnet (0xf9beb4d9)
-        if magic_bytes != [0xf9, 0xbe, 0xb4, 0xd9] {
-            return Err(anyhow!("Invalid magic bytes at offset {}: {:02x?}", current_offset, magic_bytes));
+        let magic_and_size: [u8; 8] = mmap[start..start + 8].try_into().unwrap();
+        let Some(block_size) = parse_block_frame(magic_and_size, offset, &self.xor_key)? else {
+            return Ok(None);
+        };
+        let data_start = start + 8;
+        if data_start + block_size > mmap.len() {
+            return Err(anyhow!("Block at offset {} claims {} bytes, but the file ends after {}",
+                               offset, block_size, mmap.len() - data_start));
         }
 
-        let block_size = u32::from_le_bytes([size_bytes[0], size_bytes[1], size_bytes[2], size_bytes[3]]) as usize;
+        let length = block_size.min(limit);
+        self.buffer.clear();
+        self.buffer.extend_from_slice(&mmap[data_start..data_start + length]);
+        deobfuscate(&mut self.buffer, offset + 8, &self.xor_key);
 
+        Ok(Some(block_size))
+    }
 
-        // Read the block header (80 bytes)
-        let mut header_data = [0u8; 80];
-        self.reader.read_exact(&mut header_data)?;
+    // Read the block an index entry points at, confirming it is the block the index expects
+    // and, with check_merkle, that its transactions match the merkle root and witness commitment
+    pub fn read_block_at(&mut self, height: u32, location: &BlockLocation, check_merkle: bool) -> std::result::Result<Block, BlockReadError> {
+        let error = |kind| BlockReadError {
+            height,
+            file_path: location.file_path.clone(),
+            file_offset: location.file_offset,
+            expected_hash: location.block_hash,
+            kind,
+        };
 
-        // Deobfuscate the header data
-        self.deobfuscate_data(&mut header_data, current_offset + 8);
+        let block_size = self.load_block(&location.file_path, location.file_offset, usize::MAX)
+            .map_err(|e| error(BlockReadErrorKind::Unreadable(e.to_string())))?
+            .ok_or_else(|| error(BlockReadErrorKind::Missing))?;
+        if block_size as u32 != location.block_size {
+            return Err(error(BlockReadErrorKind::SizeMismatch { found: block_size as u32, expected: location.block_size }));
+        }
+        let block: Block = bitcoin::consensus::deserialize(&self.buffer)
+            .map_err(|e| error(BlockReadErrorKind::Unreadable(e.to_string())))?;
 
-        // Parse header using bitcoin crate
-        let header: Header = bitcoin::consensus::deserialize(&header_data)?;
+        let found = block.block_hash();
+        if found != location.block_hash {
+            return Err(error(BlockReadErrorKind::HashMismatch { found }));
+        }
+        if check_merkle {
+            if !block.check_merkle_root() {
+                return Err(error(BlockReadErrorKind::MerkleRootMismatch));
+            }
+            if !block.check_witness_commitment() {
+                return Err(error(BlockReadErrorKind::WitnessCommitmentMismatch));
+            }
+        }
 
-        // Skip the remaining transaction data
-        let remaining_bytes = block_size - 80;
-        self.reader.seek(SeekFrom::Current(remaining_bytes as i64))?;
+        Ok(block)
+    }
+
+    // Header-only counterpart of read_block_at, copying just the first 80 bytes of the block
+    pub fn read_header_at(&mut self, height: u32, location: &BlockLocation) -> std::result::Result<Header, BlockReadError> {
+        let error = |kind| BlockReadError {
+            height,
+            file_path: location.file_path.clone(),
+            file_offset: location.file_offset,
+            expected_hash: location.block_hash,
+            kind,
+        };
+
+        let block_size = self.load_block(&location.file_path, location.file_offset, 80)
+            .map_err(|e| error(BlockReadErrorKind::Unreadable(e.to_string())))?
+            .ok_or_else(|| error(BlockReadErrorKind::Missing))?;
+        if block_size as u32 != location.block_size {
+            return Err(error(BlockReadErrorKind::SizeMismatch { found: block_size as u32, expected: location.block_size }));
+        }
+        let header: Header = bitcoin::consensus::deserialize(&self.buffer)
+            .map_err(|e| error(BlockReadErrorKind::Unreadable(e.to_string())))?;
 
+        let found = header.block_hash();
+        if found != location.block_hash {
+            return Err(error(BlockReadErrorKind::HashMismatch { found }));
+        }
 
-        Ok(Some((header, current_offset, block_size as u32)))
+        Ok(header)
     }
+}
+
+// Cursor over raw block bytes that only follows length prefixes
+struct ByteScanner<'a> {
+    data: &'a [u8],
+    position: usize,
+}
 
-    pub fn seek_to_offset(&mut self, offset: u64) -> Result<()> {
-        self.reader.seek(SeekFrom::Start(offset))?;
+impl ByteScanner<'_> {
+    fn skip(&mut self, count: usize) -> Result<()> {
+        if self.data.len() - self.position < count {
+            return Err(anyhow!("unexpected end of data at byte {}", self.position));
+        }
+        self.position += count;
         Ok(())
     }
 
-    pub fn file_path(&self) -> &str {
-        &self.file_path
+    fn read_u8(&mut self) -> Result<u8> {
+        let byte = *self.data.get(self.position)
+            .ok_or_else(|| anyhow!("unexpected end of data at byte {}", self.position))?;
+        self.position += 1;
+        Ok(byte)
+    }
+
+    fn peek_u8(&self, ahead: usize) -> Option<u8> {
+        self.data.get(self.position + ahead).copied()
+    }
+
+    // Bitcoin CompactSize integer
+    fn read_compact_size(&mut self) -> Result<u64> {
+        let width = match self.read_u8()? {
+            0xfd => 2,
+            0xfe => 4,
+            0xff => 8,
+            value => return Ok(value as u64),
+        };
+        let start = self.position;
+        self.skip(width)?;
+        let mut bytes = [0u8; 8];
+        bytes[..width].copy_from_slice(&self.data[start..start + width]);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn skip_prefixed(&mut self) -> Result<()> {
+        let len = self.read_compact_size()?;
+        self.skip(usize::try_from(len)?)
+    }
+}
+
+// Count the transactions following a block header and total their witness bytes
+// (segwit marker and flag included), without building Transaction values
+fn scan_transactions(data: &[u8]) -> Result<(u32, u64)> {
+    let mut scanner = ByteScanner { data, position: 0 };
+    let tx_count = scanner.read_compact_size()?;
+    let mut witness_bytes = 0u64;
+
+    for _ in 0..tx_count {
+        scanner.skip(4)?; // version
+
+        // Segwit serialization: marker 0x00 followed by flag 0x01 where the input count would be
+        let segwit = scanner.peek_u8(0) == Some(0x00) && scanner.peek_u8(1) == Some(0x01);
+        if segwit {
+            scanner.skip(2)?;
+            witness_bytes += 2;
+        }
+
+        let input_count = scanner.read_compact_size()?;
+        for _ in 0..input_count {
+            scanner.skip(36)?; // previous outpoint
+            scanner.skip_prefixed()?; // scriptSig
+            scanner.skip(4)?; // sequence
+        }
+
+        let output_count = scanner.read_compact_size()?;
+        for _ in 0..output_count {
+            scanner.skip(8)?; // value
+            scanner.skip_prefixed()?; // scriptPubKey
+        }
+
+        if segwit {
+            let witness_start = scanner.position;
+            for _ in 0..input_count {
+                let item_count = scanner.read_compact_size()?;
+                for _ in 0..item_count {
+                    scanner.skip_prefixed()?;
+                }
+            }
+            witness_bytes += (scanner.position - witness_start) as u64;
+        }
+
+        scanner.skip(4)?; // lock time
+    }
+
+    Ok((u32::try_from(tx_count)?, witness_bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const XOR_KEY: [u8; 8] = [0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef];
+
+    // The straightforward per-byte version deobfuscate must agree with
+    fn deobfuscate_per_byte(data: &mut [u8], offset: u64, xor_key: &[u8; 8]) {
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte ^= xor_key[(offset as usize + i) % 8];
+        }
+    }
+
+    #[test]
+    fn deobfuscate_matches_per_byte_at_every_alignment() {
+        let original: Vec<u8> = (0..=255u8).cycle().take(64).collect();
+        for offset in 0..16u64 {
+            for length in 0..original.len() {
+                let mut expected = original[..length].to_vec();
+                deobfuscate_per_byte(&mut expected, offset, &XOR_KEY);
+                let mut actual = original[..length].to_vec();
+                deobfuscate(&mut actual, offset, &XOR_KEY);
+                assert_eq!(actual, expected, "offset {} length {}", offset, length);
+            }
+        }
+    }
+
+    // Write data to a temporary block file, obfuscated with XOR_KEY, and open a reader on it
+    fn obfuscated_file_reader(name: &str, mut data: Vec<u8>) -> (BlockFileReader, std::path::PathBuf) {
+        let path = std::env::temp_dir().join(format!("block_parser_{}_{}.dat", name, std::process::id()));
+        deobfuscate(&mut data, 0, &XOR_KEY);
+        std::fs::write(&path, &data).unwrap();
+        (BlockFileReader::new_with_xor_key(&path, XOR_KEY).unwrap(), path)
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn find_magic_across_chunk_boundary() {
+        let chunk = MAGIC_SEARCH_CHUNK_SIZE as usize;
+        let mut data = vec![0x55u8; chunk + 64];
+        // Every split of the magic across the first chunk boundary
+        for split in 1..4 {
+            let magic_offset = chunk - split;
+            data[magic_offset..magic_offset + 4].copy_from_slice(&[0xf9, 0xbe, 0xb4, 0xd9]);
+            let (mut reader, path) = obfuscated_file_reader(&format!("split{}", split), data.clone());
+            assert_eq!(reader.find_magic(0).unwrap(), Some(magic_offset as u64), "split {}", split);
+            assert_eq!(reader.find_magic(magic_offset as u64 + 1).unwrap(), None, "split {}", split);
+            std::fs::remove_file(path).unwrap();
+            data[magic_offset..magic_offset + 4].copy_from_slice(&[0x55; 4]);
+        }
+    }
+
+    #[test]
+    fn find_magic_at_end_of_file() {
+        let mut data = vec![0x55u8; 100];
+        data[96..100].copy_from_slice(&[0xf9, 0xbe, 0xb4, 0xd9]);
+        let (mut reader, path) = obfuscated_file_reader("end", data);
+        assert_eq!(reader.find_magic(0).unwrap(), Some(96));
+        assert_eq!(reader.find_magic(96).unwrap(), Some(96));
+        assert_eq!(reader.find_magic(97).unwrap(), None);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn deobfuscate_with_zero_key_is_identity() {
+        let original: Vec<u8> = (0..37).collect();
+        let mut data = original.clone();
+        deobfuscate(&mut data, 5, &[0; 8]);
+        assert_eq!(data, original);
+    }
+}
@@ -0,0 +1,178 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use anyhow::Result;
+
+const BUCKET_COUNT: usize = 256;
+
+// Bytes buffered per bucket before they are appended to the spill file as one run
+const RUN_SIZE: usize = 64 * 1024;
+
+// Size of the table of cumulative bucket starts written ahead of the sorted entries
+pub const FANOUT_SIZE: usize = (BUCKET_COUNT + 1) * 8;
+
+// Groups fixed-size entries by leading byte into runs appended to a single spill file,
+// then writes them back out fully sorted while holding only one bucket (1/256 of the
+// entries) in memory. Only one file descriptor is held per sorter
+pub struct BucketSorter<const N: usize> {
+    dir: PathBuf,
+    spill: File,
+    spill_len: u64,
+    buffers: Vec<Vec<u8>>,
+    runs: Vec<Vec<(u64, usize)>>, // (offset, length) of each spilled run, per bucket
+    counts: Vec<u64>,
+}
+
+impl<const N: usize> BucketSorter<N> {
+    pub fn new(dir: PathBuf) -> Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let spill = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(dir.join("spill"))?;
+
+        Ok(BucketSorter {
+            dir,
+            spill,
+            spill_len: 0,
+            buffers: vec![Vec::new(); BUCKET_COUNT],
+            runs: vec![Vec::new(); BUCKET_COUNT],
+            counts: vec![0; BUCKET_COUNT],
+        })
+    }
+
+    pub fn add(&mut self, entry: &[u8; N]) -> Result<()> {
+        let bucket = entry[0] as usize;
+        self.buffers[bucket].extend_from_slice(entry);
+        self.counts[bucket] += 1;
+        if self.buffers[bucket].len() + N > RUN_SIZE {
+            self.spill_run(bucket)?;
+        }
+        Ok(())
+    }
+
+    fn spill_run(&mut self, bucket: usize) -> Result<()> {
+        let buffer = &mut self.buffers[bucket];
+        if buffer.is_empty() {
+            return Ok(());
+        }
+        self.spill.write_all(buffer)?;
+        self.runs[bucket].push((self.spill_len, buffer.len()));
+        self.spill_len += buffer.len() as u64;
+        buffer.clear();
+        Ok(())
+    }
+
+    pub fn len(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    // Visit every entry in byte order, removing the temporary files afterwards
+    pub fn for_each_sorted<F: FnMut(&[u8; N]) -> Result<()>>(mut self, mut visit: F) -> Result<()> {
+        for bucket in 0..BUCKET_COUNT {
+            // Read the bucket's runs straight into place, followed by its unspilled tail
+            let mut entries = vec![[0u8; N]; self.counts[bucket] as usize];
+            let bytes = entries.as_flattened_mut();
+            let mut filled = 0;
+            for &(offset, length) in &self.runs[bucket] {
+                self.spill.seek(SeekFrom::Start(offset))?;
+                self.spill.read_exact(&mut bytes[filled..filled + length])?;
+                filled += length;
+            }
+            let tail = std::mem::take(&mut self.buffers[bucket]);
+            bytes[filled..].copy_from_slice(&tail);
+
+            entries.sort_unstable();
+            for entry in &entries {
+                visit(entry)?;
+            }
+        }
+
+        drop(self.spill);
+        fs::remove_file(self.dir.join("spill"))?;
+        fs::remove_dir(&self.dir)?;
+
+        Ok(())
+    }
+
+    // Write the fanout table followed by every entry in byte order, removing the temporary files
+    pub fn finish<W: Write>(self, writer: &mut W) -> Result<()> {
+        let mut start = 0u64;
+        writer.write_all(&start.to_le_bytes())?;
+        for count in &self.counts {
+            start += count;
+            writer.write_all(&start.to_le_bytes())?;
+        }
+
+        self.for_each_sorted(|entry| Ok(writer.write_all(entry)?))
+    }
+}
+
+// The contiguous run of N-byte entries starting with prefix, in a table written by
+// BucketSorter::finish (fanout table included)
+pub fn find_prefix<'a, const N: usize>(table: &'a [u8], prefix: &[u8]) -> &'a [u8] {
+    let fanout = |bucket: usize| {
+        let start = bucket * 8;
+        u64::from_le_bytes(table[start..start + 8].try_into().unwrap()) as usize
+    };
+    let entry = |index: usize| {
+        let start = FANOUT_SIZE + index * N;
+        &table[start..start + N]
+    };
+
+    // Binary search within the prefix's bucket for the first entry not below the prefix
+    let bucket = prefix[0] as usize;
+    let bucket_end = fanout(bucket + 1);
+    let (mut low, mut high) = (fanout(bucket), bucket_end);
+    while low < high {
+        let middle = low + (high - low) / 2;
+        if &entry(middle)[..prefix.len()] < prefix {
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+
+    let mut end = low;
+    while end < bucket_end && &entry(end)[..prefix.len()] == prefix {
+        end += 1;
+    }
+
+    &table[FANOUT_SIZE + low * N..FANOUT_SIZE + end * N]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sorts_entries_spread_over_several_runs() {
+        let dir = std::env::temp_dir().join(format!("bucket_sort_test_{}", std::process::id()));
+        let mut sorter = BucketSorter::<4>::new(dir.clone()).unwrap();
+
+        // Enough entries for bucket 0x07 to spill several runs, plus a short tail elsewhere
+        let mut expected = Vec::new();
+        for i in (0..40_000u32).rev() {
+            let entry = [0x07, (i >> 16) as u8, (i >> 8) as u8, i as u8];
+            sorter.add(&entry).unwrap();
+            expected.push(entry);
+        }
+        for entry in [[0xff, 0, 0, 2], [0x00, 9, 9, 9], [0xff, 0, 0, 1]] {
+            sorter.add(&entry).unwrap();
+            expected.push(entry);
+        }
+        expected.sort_unstable();
+        assert_eq!(sorter.len(), expected.len() as u64);
+
+        let mut sorted = Vec::new();
+        sorter.for_each_sorted(|entry| {
+            sorted.push(*entry);
+            Ok(())
+        }).unwrap();
+
+        assert_eq!(sorted, expected);
+        assert!(!dir.exists());
+    }
+}
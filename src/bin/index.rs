@@ -1,61 +1,510 @@
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::{BufReader, BufWriter};
+use std::io::{BufReader, BufWriter, Read, Write};
 use std::path::Path;
-use anyhow::Result;
-use bitcoin::BlockHash;
+use anyhow::{Result, anyhow};
+use bitcoin::{BlockHash, Network};
+use bitcoin::block::Header;
+use bitcoin::hashes::{Hash, sha256};
+use bitcoin::p2p::Magic;
+use memmap2::Mmap;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+// On-disk layout (all integers little-endian):
+//
+//   header:     magic "BIDX" | version u32 | network magic [u8; 4] | datadir fingerprint u64
+//               | XOR key fingerprint u64 | flags u32 | record_count u32 | block_count u32 | file_count u32
+//   file table: file_count x (path_len u32 | path bytes)
+//   records:    record_count x RECORD_SIZE, one per height starting at 0
+//
+// Each record is file_number u32 | block_size u32 | file_offset u64 | block_hash [u8; 32]
+// | block header [u8; 80] | tx_count u32 | weight u32. Heights without a block have
+// file_number = MISSING_FILE, and records without a summary have tx_count = 0 (every
+// block has a coinbase). Unknown network and fingerprints (indexes migrated from the
+// legacy bincode format) are stored as zeros.
+const INDEX_MAGIC: [u8; 4] = *b"BIDX";
+pub const INDEX_VERSION: u32 = 1;
+const RECORD_SIZE: usize = 136;
+const MISSING_FILE: u32 = u32::MAX;
+
+// Header flag: every block in the index carries a summary
+const FLAG_SUMMARIES: u32 = 1;
+
+// Where an index came from, recorded so it is not used against the wrong chain or datadir
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexMetadata {
+    pub network: Option<Network>,
+    pub datadir_fingerprint: Option<u64>,
+    pub xor_key_fingerprint: Option<u64>,
+}
+
+impl IndexMetadata {
+    pub fn unknown() -> Self {
+        IndexMetadata {
+            network: None,
+            datadir_fingerprint: None,
+            xor_key_fingerprint: None,
+        }
+    }
+}
+
+fn fingerprint(data: &[u8]) -> u64 {
+    let hash = sha256::Hash::hash(data);
+    u64::from_le_bytes(hash.as_byte_array()[0..8].try_into().unwrap())
+}
+
+// Fingerprint of the data directory's canonical path
+pub fn datadir_fingerprint(datadir: &Path) -> u64 {
+    let canonical = datadir.canonicalize().unwrap_or_else(|_| datadir.to_path_buf());
+    fingerprint(canonical.to_string_lossy().as_bytes())
+}
+
+pub fn xor_key_fingerprint(xor_key: &[u8; 8]) -> u64 {
+    fingerprint(xor_key)
+}
+
+// Identify the network from its genesis block hash
+pub fn network_from_genesis(genesis_hash: BlockHash) -> Option<Network> {
+    [Network::Bitcoin, Network::Testnet, Network::Signet, Network::Regtest].into_iter()
+        .find(|&network| bitcoin::blockdata::constants::genesis_block(network).block_hash() == genesis_hash)
+}
+
+#[derive(Debug, Clone)]
 pub struct BlockLocation {
     pub file_path: String,
     pub file_offset: u64,
     pub block_hash: BlockHash,
     pub block_size: u32,
+    pub summary: Option<BlockSummary>, // None for blocks migrated from the legacy index
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct BlockIndex {
-    pub blocks: HashMap<u32, BlockLocation>, // height -> location
-    pub tip_height: u32,
+// What build-index learns about a block beyond its location, enough to answer
+// header and block-size questions without opening blk files
+#[derive(Debug, Clone, Copy)]
+pub struct BlockSummary {
+    pub header: Header,
+    pub tx_count: u32,
+    pub weight: u32,
 }
 
-impl BlockIndex {
-    pub fn new() -> Self {
-        BlockIndex {
-            blocks: HashMap::new(),
-            tip_height: 0,
+// A location whose file path has been replaced by its number in the file table
+struct Record {
+    file_number: u32,
+    file_offset: u64,
+    block_hash: BlockHash,
+    block_size: u32,
+    summary: Option<BlockSummary>,
+}
+
+// Collects block locations during build-index and writes them in the compact format
+pub struct BlockIndexBuilder {
+    metadata: IndexMetadata,
+    files: Vec<String>,
+    file_numbers: HashMap<String, u32>,
+    records: Vec<Option<Record>>, // indexed by height
+    block_count: u32,
+    summary_count: u32,
+}
+
+impl BlockIndexBuilder {
+    pub fn new(metadata: IndexMetadata) -> Self {
+        BlockIndexBuilder {
+            metadata,
+            files: Vec::new(),
+            file_numbers: HashMap::new(),
+            records: Vec::new(),
+            block_count: 0,
+            summary_count: 0,
         }
     }
 
     pub fn add_block(&mut self, height: u32, location: BlockLocation) {
-        self.blocks.insert(height, location);
-        if height > self.tip_height {
-            self.tip_height = height;
+        let file_number = match self.file_numbers.get(&location.file_path) {
+            Some(&number) => number,
+            None => {
+                let number = self.files.len() as u32;
+                self.file_numbers.insert(location.file_path.clone(), number);
+                self.files.push(location.file_path);
+                number
+            }
+        };
+
+        if height as usize >= self.records.len() {
+            self.records.resize_with(height as usize + 1, || None);
+        }
+        if let Some(previous) = &self.records[height as usize] {
+            self.block_count -= 1;
+            self.summary_count -= previous.summary.is_some() as u32;
         }
+        self.block_count += 1;
+        self.summary_count += location.summary.is_some() as u32;
+
+        self.records[height as usize] = Some(Record {
+            file_number,
+            file_offset: location.file_offset,
+            block_hash: location.block_hash,
+            block_size: location.block_size,
+            summary: location.summary,
+        });
     }
 
-    pub fn get_block_location(&self, height: u32) -> Option<&BlockLocation> {
-        self.blocks.get(&height)
+    pub fn len(&self) -> u32 {
+        self.block_count
     }
 
+    // Write to a temporary file first so an interrupted save never leaves a truncated index behind
     pub fn save_to_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
-        let file = File::create(path)?;
-        let writer = BufWriter::new(file);
-        bincode::serialize_into(writer, self)?;
+        let temp_path = path.as_ref().with_extension("idx.tmp");
+        let mut writer = BufWriter::new(File::create(&temp_path)?);
+
+        writer.write_all(&INDEX_MAGIC)?;
+        writer.write_all(&INDEX_VERSION.to_le_bytes())?;
+        writer.write_all(&self.metadata.network.map_or([0; 4], |network| network.magic().to_bytes()))?;
+        writer.write_all(&self.metadata.datadir_fingerprint.unwrap_or(0).to_le_bytes())?;
+        writer.write_all(&self.metadata.xor_key_fingerprint.unwrap_or(0).to_le_bytes())?;
+        let flags = if self.block_count > 0 && self.summary_count == self.block_count { FLAG_SUMMARIES } else { 0 };
+        writer.write_all(&flags.to_le_bytes())?;
+        writer.write_all(&(self.records.len() as u32).to_le_bytes())?;
+        writer.write_all(&self.block_count.to_le_bytes())?;
+        writer.write_all(&(self.files.len() as u32).to_le_bytes())?;
+
+        for file_path in &self.files {
+            writer.write_all(&(file_path.len() as u32).to_le_bytes())?;
+            writer.write_all(file_path.as_bytes())?;
+        }
+
+        for record in &self.records {
+            let mut bytes = [0u8; RECORD_SIZE];
+            match record {
+                Some(record) => {
+                    bytes[0..4].copy_from_slice(&record.file_number.to_le_bytes());
+                    bytes[4..8].copy_from_slice(&record.block_size.to_le_bytes());
+                    bytes[8..16].copy_from_slice(&record.file_offset.to_le_bytes());
+                    bytes[16..48].copy_from_slice(record.block_hash.as_byte_array());
+                    if let Some(summary) = &record.summary {
+                        bytes[48..128].copy_from_slice(&bitcoin::consensus::serialize(&summary.header));
+                        bytes[128..132].copy_from_slice(&summary.tx_count.to_le_bytes());
+                        bytes[132..136].copy_from_slice(&summary.weight.to_le_bytes());
+                    }
+                }
+                None => bytes[0..4].copy_from_slice(&MISSING_FILE.to_le_bytes()),
+            }
+            writer.write_all(&bytes)?;
+        }
+
+        writer.into_inner()?.sync_all()?;
+        std::fs::rename(&temp_path, path)?;
         Ok(())
     }
+}
+
+// Memory-mapped, read-only view of a saved index. Records are decoded on demand,
+// so opening the index costs the same regardless of chain length.
+pub struct BlockIndex {
+    mmap: Mmap,
+    pub version: u32,
+    pub metadata: IndexMetadata,
+    pub has_summaries: bool, // every block carries a BlockSummary
+    files: Vec<String>,
+    records_offset: usize,
+    record_count: u32,
+    block_count: u32,
+    pub tip_height: u32,
+}
+
+// An index file as stored on disk, before any migration or upgrade
+pub enum StoredIndex {
+    Legacy { block_count: usize, tip_height: u32 },
+    Compact(BlockIndex),
+}
 
+fn is_compact_index(path: &Path) -> Result<bool> {
+    let mut magic = [0u8; 4];
+    Ok(File::open(path)?.read_exact(&mut magic).is_ok() && magic == INDEX_MAGIC)
+}
+
+impl BlockIndex {
     pub fn load_from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+
+        if !is_compact_index(path)? {
+            migrate_legacy_index(path)?;
+        }
+
+        Self::map(path)
+    }
+
+    // Open an index exactly as stored, never rewriting it, for commands that only inspect it
+    pub fn inspect<P: AsRef<Path>>(path: P) -> Result<StoredIndex> {
+        let path = path.as_ref();
+        if is_compact_index(path)? {
+            return Ok(StoredIndex::Compact(Self::map(path)?));
+        }
+
+        let legacy = read_legacy_index(path)?;
+        Ok(StoredIndex::Legacy {
+            block_count: legacy.blocks.len(),
+            tip_height: legacy.tip_height,
+        })
+    }
+
+    fn map(path: &Path) -> Result<Self> {
         let file = File::open(path)?;
-        let reader = BufReader::new(file);
-        let index = bincode::deserialize_from(reader)?;
-        Ok(index)
+        // SAFETY: the index is only ever replaced by rename, never modified in place,
+        // so the mapped contents cannot change underneath us.
+        let mmap = unsafe { Mmap::map(&file)? };
+        Self::from_mmap(mmap, path)
+    }
+
+    fn from_mmap(mmap: Mmap, path: &Path) -> Result<Self> {
+        let truncated = || anyhow!("Index file {} is truncated", path.display());
+        let read_u32 = |offset: usize| -> Result<u32> {
+            let bytes = mmap.get(offset..offset + 4).ok_or_else(truncated)?;
+            Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+        };
+
+        let read_u64 = |offset: usize| -> Result<u64> {
+            let bytes = mmap.get(offset..offset + 8).ok_or_else(truncated)?;
+            Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+        };
+
+        let version = read_u32(4)?;
+        if version > INDEX_VERSION {
+            return Err(anyhow!("Index file {} uses format version {}, but this build only understands version {}. \
+                                Please upgrade this tool or rebuild the index with build-index.",
+                               path.display(), version, INDEX_VERSION));
+        }
+        if version != INDEX_VERSION {
+            return Err(anyhow!("Index file {} has unknown format version {}. Please rebuild it with build-index.",
+                               path.display(), version));
+        }
+
+        let network_magic: [u8; 4] = mmap.get(8..12).ok_or_else(truncated)?.try_into().unwrap();
+        let known = |value: u64| (value != 0).then_some(value);
+        let metadata = IndexMetadata {
+            network: Network::from_magic(Magic::from_bytes(network_magic)),
+            datadir_fingerprint: known(read_u64(12)?),
+            xor_key_fingerprint: known(read_u64(20)?),
+        };
+        let flags = read_u32(28)?;
+        let record_count = read_u32(32)?;
+        let block_count = read_u32(36)?;
+        let file_count = read_u32(40)?;
+
+        let mut files = Vec::with_capacity(file_count as usize);
+        let mut offset = 44;
+        for _ in 0..file_count {
+            let len = read_u32(offset)? as usize;
+            let bytes = mmap.get(offset + 4..offset + 4 + len).ok_or_else(truncated)?;
+            files.push(String::from_utf8(bytes.to_vec())?);
+            offset += 4 + len;
+        }
+
+        if mmap.len() != offset + record_count as usize * RECORD_SIZE {
+            return Err(anyhow!("Index file {} has {} bytes, expected {} for {} records",
+                               path.display(), mmap.len(), offset + record_count as usize * RECORD_SIZE, record_count));
+        }
+
+        Ok(BlockIndex {
+            mmap,
+            version,
+            metadata,
+            has_summaries: flags & FLAG_SUMMARIES != 0,
+            files,
+            records_offset: offset,
+            record_count,
+            block_count,
+            tip_height: record_count.saturating_sub(1),
+        })
+    }
+
+    pub fn len(&self) -> u32 {
+        self.block_count
+    }
+
+    pub fn file_count(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn get_block_location(&self, height: u32) -> Option<BlockLocation> {
+        if height >= self.record_count {
+            return None;
+        }
+
+        let start = self.records_offset + height as usize * RECORD_SIZE;
+        let record = &self.mmap[start..start + RECORD_SIZE];
+
+        let file_number = u32::from_le_bytes(record[0..4].try_into().unwrap());
+        if file_number == MISSING_FILE {
+            return None;
+        }
+
+        Some(BlockLocation {
+            file_path: self.files.get(file_number as usize)?.clone(),
+            block_size: u32::from_le_bytes(record[4..8].try_into().unwrap()),
+            file_offset: u64::from_le_bytes(record[8..16].try_into().unwrap()),
+            block_hash: BlockHash::from_byte_array(record[16..48].try_into().unwrap()),
+            summary: Self::decode_summary(record),
+        })
+    }
+
+    fn decode_summary(record: &[u8]) -> Option<BlockSummary> {
+        let tx_count = u32::from_le_bytes(record[128..132].try_into().unwrap());
+        if tx_count == 0 {
+            return None;
+        }
+
+        Some(BlockSummary {
+            header: bitcoin::consensus::deserialize(&record[48..128]).ok()?,
+            tx_count,
+            weight: u32::from_le_bytes(record[132..136].try_into().unwrap()),
+        })
+    }
+
+    pub fn iter_reverse(&self) -> impl Iterator<Item = (u32, BlockLocation)> + '_ {
+        (0..self.record_count).rev()
+            .filter_map(move |height| self.get_block_location(height).map(|location| (height, location)))
+    }
+}
+
+// The original index format: a bincode-serialized map from height to location
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(serde::Serialize))]
+struct LegacyBlockLocation {
+    file_path: String,
+    file_offset: u64,
+    block_hash: BlockHash,
+    block_size: u32,
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(serde::Serialize))]
+struct LegacyBlockIndex {
+    blocks: HashMap<u32, LegacyBlockLocation>,
+    tip_height: u32,
+}
+
+fn read_legacy_index(path: &Path) -> Result<LegacyBlockIndex> {
+    let reader = BufReader::new(File::open(path)?);
+    bincode::deserialize_from(reader)
+        .map_err(|e| anyhow!("Index file {} is neither a compact nor a legacy index ({}). Please rebuild it with build-index.",
+                             path.display(), e))
+}
+
+// Rewrite a bincode index from before the compact format in place
+fn migrate_legacy_index(path: &Path) -> Result<()> {
+    let legacy = read_legacy_index(path)?;
+
+    println!("Migrating legacy index {} ({} blocks) to the compact format...", path.display(), legacy.blocks.len());
+
+    let mut builder = BlockIndexBuilder::new(IndexMetadata::unknown());
+    for (height, location) in legacy.blocks {
+        builder.add_block(height, BlockLocation {
+            file_path: location.file_path,
+            file_offset: location.file_offset,
+            block_hash: location.block_hash,
+            block_size: location.block_size,
+            summary: None,
+        });
+    }
+    builder.save_to_file(path)?;
+
+    println!("Migration complete");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn temp_index_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("index_test_{}_{}.idx", std::process::id(), name))
+    }
+
+    fn location(file_path: &str, file_offset: u64, hash_byte: u8, summary: Option<BlockSummary>) -> BlockLocation {
+        BlockLocation {
+            file_path: file_path.to_string(),
+            file_offset,
+            block_hash: BlockHash::from_byte_array([hash_byte; 32]),
+            block_size: 285,
+            summary,
+        }
+    }
+
+    #[test]
+    fn round_trips_locations_summaries_and_metadata() {
+        let path = temp_index_path("round_trip");
+        let metadata = IndexMetadata {
+            network: Some(Network::Bitcoin),
+            datadir_fingerprint: Some(0x0123_4567_89ab_cdef),
+            xor_key_fingerprint: Some(42),
+        };
+        let genesis = bitcoin::blockdata::constants::genesis_block(Network::Bitcoin);
+        let summary = BlockSummary { header: genesis.header, tx_count: 1, weight: 1140 };
+
+        // Height 1 is left without a block
+        let mut builder = BlockIndexBuilder::new(metadata);
+        builder.add_block(2, location("blk00001.dat", 8, 2, None));
+        builder.add_block(0, location("blk00000.dat", 8, 0, Some(summary)));
+        assert_eq!(builder.len(), 2);
+        builder.save_to_file(&path).unwrap();
+
+        let index = BlockIndex::load_from_file(&path).unwrap();
+        assert_eq!(index.version, INDEX_VERSION);
+        assert_eq!(index.metadata, metadata);
+        assert_eq!(index.len(), 2);
+        assert_eq!(index.tip_height, 2);
+        assert_eq!(index.file_count(), 2);
+        assert!(!index.has_summaries);
+        assert!(index.get_block_location(1).is_none());
+        assert!(index.get_block_location(3).is_none());
+
+        let genesis_location = index.get_block_location(0).unwrap();
+        assert_eq!(genesis_location.file_path, "blk00000.dat");
+        assert_eq!(genesis_location.block_hash, BlockHash::from_byte_array([0; 32]));
+        let stored = genesis_location.summary.unwrap();
+        assert_eq!(stored.header, genesis.header);
+        assert_eq!((stored.tx_count, stored.weight), (1, 1140));
+
+        let tip = index.get_block_location(2).unwrap();
+        assert_eq!((tip.file_path.as_str(), tip.file_offset, tip.block_size), ("blk00001.dat", 8, 285));
+        assert!(tip.summary.is_none());
+
+        let heights: Vec<u32> = index.iter_reverse().map(|(height, _)| height).collect();
+        assert_eq!(heights, vec![2, 0]);
+
+        std::fs::remove_file(&path).unwrap();
     }
 
-    pub fn iter_reverse(&self) -> impl Iterator<Item = (&u32, &BlockLocation)> {
-        let mut heights: Vec<_> = self.blocks.keys().collect();
-        heights.sort_by(|a, b| b.cmp(a)); // Sort in descending order
-        heights.into_iter().map(move |height| (height, &self.blocks[height]))
+    #[test]
+    fn migrates_a_legacy_index_in_place() {
+        let path = temp_index_path("legacy");
+        let blocks = (0..3u32)
+            .map(|height| (height, LegacyBlockLocation {
+                file_path: "blk00000.dat".to_string(),
+                file_offset: 8 + height as u64 * 293,
+                block_hash: BlockHash::from_byte_array([height as u8; 32]),
+                block_size: 285,
+            }))
+            .collect();
+        let legacy = LegacyBlockIndex { blocks, tip_height: 2 };
+        std::fs::write(&path, bincode::serialize(&legacy).unwrap()).unwrap();
+
+        match BlockIndex::inspect(&path).unwrap() {
+            StoredIndex::Legacy { block_count, tip_height } => assert_eq!((block_count, tip_height), (3, 2)),
+            StoredIndex::Compact(_) => panic!("legacy index inspected as compact"),
+        }
+
+        let index = BlockIndex::load_from_file(&path).unwrap();
+        assert!(is_compact_index(&path).unwrap());
+        assert_eq!(index.metadata, IndexMetadata::unknown());
+        assert_eq!((index.len(), index.tip_height), (3, 2));
+        let location = index.get_block_location(1).unwrap();
+        assert_eq!(location.file_offset, 301);
+        assert_eq!(location.block_hash, BlockHash::from_byte_array([1; 32]));
+        assert!(location.summary.is_none());
+
+        std::fs::remove_file(&path).unwrap();
     }
-}
\ No newline at end of file
+}
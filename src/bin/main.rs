@@ -1,16 +1,23 @@
 use clap::{Parser, Subcommand};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::collections::{HashMap, HashSet};
 use std::hash::{Hash, Hasher};
 use std::io;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use bitcoin::BlockHash;
 use bitcoin::hashes::Hash as BitcoinHash;
-use bitcoin::{Amount, Transaction};
-use block_parser::BlockFileReader;
-use index::{BlockIndex, BlockLocation};
-
-const INDEX_PATH: &str = "blockchain.idx";
+use bitcoin::{Amount, SignedAmount, Transaction};
+use block_parser::{BlockFileReader, MappedBlockReader, SkippedRange};
+use index::{BlockIndex, BlockIndexBuilder, BlockLocation, BlockSummary, IndexMetadata, StoredIndex};
+use seen_scripts::{script_fingerprint, SeenScripts};
+use soft_forks::{Deployment, SignalCounter};
+use scriptindex::{Direction, ScriptHistoryEntry, ScriptIndex, ScriptIndexBuilder, SpendResolver};
+use txindex::{TxIndex, TxIndexBuilder, TxPosition};
+
+const DEFAULT_INDEX_FILENAME: &str = "blockchain.idx";
+const DEFAULT_TXINDEX_FILENAME: &str = "txindex.idx";
+const DEFAULT_SCRIPTINDEX_FILENAME: &str = "scriptindex.idx";
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
 enum BlockHeight {
@@ -20,7 +27,13 @@ enum BlockHeight {
 }
 
 mod block_parser;
+mod bucket_sort;
 mod index;
+mod scriptindex;
+mod seen_scripts;
+mod soft_forks;
+mod txindex;
+mod verify;
 
 #[derive(Parser)]
 #[command(name = "blooming-fast-utxo-set")]
@@ -35,18 +48,30 @@ enum Commands {
     BuildIndex {
         #[arg(long, default_value = "~/.bitcoin", help = "Path to Bitcoin data directory (parent of blocks/ folder)")]
         datadir: PathBuf,
+        #[arg(long, help = "Path to the block index file (default: blockchain.idx inside the data directory)")]
+        index: Option<PathBuf>,
+        #[arg(long, help = "Skip over damaged data in block files instead of stopping, and report what was skipped")]
+        recover: bool,
+        #[arg(long, help = "Number of block files to scan in parallel (default: available cores)")]
+        threads: Option<usize>,
     },
     Iterate {
         #[arg(long, default_value = "~/.bitcoin", help = "Path to Bitcoin data directory (parent of blocks/ folder)")]
         datadir: PathBuf,
+        #[arg(long, help = "Path to the block index file (default: blockchain.idx inside the data directory)")]
+        index: Option<PathBuf>,
         #[arg(long, help = "Starting block height (default: tip)")]
         start_height: Option<u32>,
         #[arg(long, help = "Ending block height (default: 0)")]
         end_height: Option<u32>,
+        #[arg(long, help = "Also check each block's merkle root and witness commitment")]
+        check_merkle: bool,
     },
     Export {
         #[arg(long, default_value = "~/.bitcoin", help = "Path to Bitcoin data directory (parent of blocks/ folder)")]
         datadir: PathBuf,
+        #[arg(long, help = "Path to the block index file (default: blockchain.idx inside the data directory)")]
+        index: Option<PathBuf>,
         #[arg(help = "Output Arrow file path")]
         filename: PathBuf,
         #[arg(help = "Column names to export (e.g., height tx_count fee_avg)")]
@@ -55,6 +80,84 @@ enum Commands {
         max_height: Option<u32>,
         #[arg(long, help = "Enable UTXO tracking for accurate per-transaction fee calculations")]
         utxo: bool,
+        #[arg(long, help = "Also check each block's merkle root and witness commitment")]
+        check_merkle: bool,
+        #[arg(long, help = "Enable tracking of previously seen scriptPubKeys for script reuse columns")]
+        script_reuse: bool,
+        #[arg(long, default_value_t = 2048, help = "Memory in MB for the seen-scripts filter used by --script-reuse (plus a quarter of this for the reused-scripts filter)")]
+        script_filter_mb: usize,
+        #[arg(long, default_value_t = 3, help = "Minimum inputs for a transaction to count as a consolidation")]
+        consolidation_min_inputs: usize,
+        #[arg(long, default_value_t = 1, help = "Maximum outputs for a transaction to count as a consolidation")]
+        consolidation_max_outputs: usize,
+        #[arg(long, default_value_t = 2, help = "Maximum inputs for a transaction to count as a batched payment")]
+        batch_max_inputs: usize,
+        #[arg(long, default_value_t = 3, help = "Minimum outputs for a transaction to count as a batched payment")]
+        batch_min_outputs: usize,
+        #[arg(long, default_value_t = DEFAULT_DUST_RELAY_FEE, help = "Dust relay fee rate in sat/kvB used to classify dust outputs")]
+        dust_relay_fee: u64,
+        #[arg(long, default_value_t = 144, help = "Number of blocks averaged over for hashrate_estimate")]
+        hashrate_window: usize,
+        #[arg(long, help = "Network whose soft-fork deployment table to use (bitcoin, testnet, signet, regtest; default: the index's network)")]
+        network: Option<bitcoin::Network>,
+        #[arg(long, help = "JSON file of soft-fork deployments per network (default: built-in table)")]
+        deployments: Option<PathBuf>,
+    },
+    IndexInfo {
+        #[arg(long, default_value = "~/.bitcoin", help = "Path to Bitcoin data directory (parent of blocks/ folder)")]
+        datadir: PathBuf,
+        #[arg(long, help = "Path to the block index file (default: blockchain.idx inside the data directory)")]
+        index: Option<PathBuf>,
+    },
+    BuildTxindex {
+        #[arg(long, default_value = "~/.bitcoin", help = "Path to Bitcoin data directory (parent of blocks/ folder)")]
+        datadir: PathBuf,
+        #[arg(long, help = "Path to the block index file (default: blockchain.idx inside the data directory)")]
+        index: Option<PathBuf>,
+        #[arg(long, help = "Path to the transaction index file (default: txindex.idx inside the data directory)")]
+        txindex: Option<PathBuf>,
+    },
+    Tx {
+        #[arg(help = "Transaction id to look up")]
+        txid: bitcoin::Txid,
+        #[arg(long, default_value = "~/.bitcoin", help = "Path to Bitcoin data directory (parent of blocks/ folder)")]
+        datadir: PathBuf,
+        #[arg(long, help = "Path to the block index file (default: blockchain.idx inside the data directory)")]
+        index: Option<PathBuf>,
+        #[arg(long, help = "Path to the transaction index file (default: txindex.idx inside the data directory)")]
+        txindex: Option<PathBuf>,
+    },
+    BuildScriptindex {
+        #[arg(long, default_value = "~/.bitcoin", help = "Path to Bitcoin data directory (parent of blocks/ folder)")]
+        datadir: PathBuf,
+        #[arg(long, help = "Path to the block index file (default: blockchain.idx inside the data directory)")]
+        index: Option<PathBuf>,
+        #[arg(long, help = "Path to the script index file (default: scriptindex.idx inside the data directory)")]
+        scriptindex: Option<PathBuf>,
+    },
+    Address {
+        #[arg(help = "Address or hex scriptPubKey to report the history of")]
+        address: String,
+        #[arg(long, default_value = "~/.bitcoin", help = "Path to Bitcoin data directory (parent of blocks/ folder)")]
+        datadir: PathBuf,
+        #[arg(long, help = "Path to the block index file (default: blockchain.idx inside the data directory)")]
+        index: Option<PathBuf>,
+        #[arg(long, help = "Path to the script index file (default: scriptindex.idx inside the data directory)")]
+        scriptindex: Option<PathBuf>,
+        #[arg(long, help = "Also export the history as an Arrow file")]
+        output: Option<PathBuf>,
+    },
+    VerifyIndex {
+        #[arg(long, default_value = "~/.bitcoin", help = "Path to Bitcoin data directory (parent of blocks/ folder)")]
+        datadir: PathBuf,
+        #[arg(long, help = "Path to the block index file (default: blockchain.idx inside the data directory)")]
+        index: Option<PathBuf>,
+        #[arg(long, help = "Also read each full block and check its merkle root (much slower)")]
+        merkle: bool,
+        #[arg(long, help = "Rescan the block files of broken entries and rewrite the index")]
+        repair: bool,
+        #[arg(long, help = "Number of verification threads (default: available cores)")]
+        threads: Option<usize>,
     },
 }
 
@@ -73,29 +176,176 @@ fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
 
     match cli.command {
-        Commands::BuildIndex { datadir } => {
+        Commands::BuildIndex { datadir, index, recover, threads } => {
             let expanded_datadir = expand_tilde(&datadir);
+            let index_path = resolve_index_path(index, &expanded_datadir, DEFAULT_INDEX_FILENAME);
             println!("Building index from data directory: {}", expanded_datadir.display());
-            build_index(expanded_datadir)?;
+            let threads = threads.unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+            build_index(expanded_datadir, index_path, recover, threads)?;
         }
-        Commands::Iterate { datadir, start_height, end_height } => {
+        Commands::Iterate { datadir, index, start_height, end_height, check_merkle } => {
             let expanded_datadir = expand_tilde(&datadir);
+            let index_path = resolve_index_path(index, &expanded_datadir, DEFAULT_INDEX_FILENAME);
             println!("Iterating blocks from {:?} to {:?}", start_height, end_height);
-            iterate_blocks(expanded_datadir, start_height, end_height)?;
+            iterate_blocks(expanded_datadir, index_path, start_height, end_height, check_merkle)?;
         }
-        Commands::Export { datadir, filename, columns, max_height, utxo } => {
+        Commands::Export {
+            datadir,
+            index,
+            filename,
+            columns,
+            max_height,
+            utxo,
+            check_merkle,
+            script_reuse,
+            script_filter_mb,
+            consolidation_min_inputs,
+            consolidation_max_outputs,
+            batch_max_inputs,
+            batch_min_outputs,
+            dust_relay_fee,
+            hashrate_window,
+            network,
+            deployments,
+        } => {
             let expanded_datadir = expand_tilde(&datadir);
+            let index_path = resolve_index_path(index, &expanded_datadir, DEFAULT_INDEX_FILENAME);
             println!("Exporting columns {:?} to {}", columns, filename.display());
             if utxo {
                 println!("🔍 UTXO tracking enabled for accurate fee calculations");
             }
-            export_arrow_file(expanded_datadir, filename, columns, max_height, utxo)?;
+            if script_reuse {
+                println!("🔍 Script reuse tracking enabled ({} MB + {} MB filters)", script_filter_mb, script_filter_mb / 4);
+            }
+            let options = ExportOptions {
+                consolidation_min_inputs,
+                consolidation_max_outputs,
+                batch_max_inputs,
+                batch_min_outputs,
+                dust_relay_fee,
+                hashrate_window,
+                network,
+                deployments_file: deployments.map(|path| expand_tilde(&path)),
+                deployments: Vec::new(), // filled in once the index's network is known
+                script_reuse,
+                script_filter_mb,
+                check_merkle,
+            };
+            export_arrow_file(expanded_datadir, index_path, filename, columns, max_height, utxo, options)?;
+        }
+        Commands::IndexInfo { datadir, index } => {
+            let expanded_datadir = expand_tilde(&datadir);
+            let index_path = resolve_index_path(index, &expanded_datadir, DEFAULT_INDEX_FILENAME);
+            print_index_info(&index_path, &expanded_datadir)?;
+        }
+        Commands::BuildTxindex { datadir, index, txindex } => {
+            let expanded_datadir = expand_tilde(&datadir);
+            let index_path = resolve_index_path(index, &expanded_datadir, DEFAULT_INDEX_FILENAME);
+            let txindex_path = resolve_index_path(txindex, &expanded_datadir, DEFAULT_TXINDEX_FILENAME);
+            build_txindex(expanded_datadir, index_path, txindex_path)?;
+        }
+        Commands::Tx { txid, datadir, index, txindex } => {
+            let expanded_datadir = expand_tilde(&datadir);
+            let index_path = resolve_index_path(index, &expanded_datadir, DEFAULT_INDEX_FILENAME);
+            let txindex_path = resolve_index_path(txindex, &expanded_datadir, DEFAULT_TXINDEX_FILENAME);
+            show_transaction(expanded_datadir, index_path, txindex_path, txid)?;
+        }
+        Commands::BuildScriptindex { datadir, index, scriptindex } => {
+            let expanded_datadir = expand_tilde(&datadir);
+            let index_path = resolve_index_path(index, &expanded_datadir, DEFAULT_INDEX_FILENAME);
+            let scriptindex_path = resolve_index_path(scriptindex, &expanded_datadir, DEFAULT_SCRIPTINDEX_FILENAME);
+            build_scriptindex(expanded_datadir, index_path, scriptindex_path)?;
+        }
+        Commands::Address { address, datadir, index, scriptindex, output } => {
+            let expanded_datadir = expand_tilde(&datadir);
+            let index_path = resolve_index_path(index, &expanded_datadir, DEFAULT_INDEX_FILENAME);
+            let scriptindex_path = resolve_index_path(scriptindex, &expanded_datadir, DEFAULT_SCRIPTINDEX_FILENAME);
+            show_address_history(expanded_datadir, index_path, scriptindex_path, &address, output)?;
+        }
+        Commands::VerifyIndex { datadir, index, merkle, repair, threads } => {
+            let expanded_datadir = expand_tilde(&datadir);
+            let index_path = resolve_index_path(index, &expanded_datadir, DEFAULT_INDEX_FILENAME);
+            let threads = threads.unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+            verify_block_index(expanded_datadir, index_path, merkle, repair, threads)?;
         }
     }
 
     Ok(())
 }
 
+// An explicit --index (or --txindex, --scriptindex) wins; otherwise each datadir keeps its own indexes alongside blocks/
+fn resolve_index_path(index: Option<PathBuf>, datadir: &Path, default_filename: &str) -> PathBuf {
+    match index {
+        Some(path) => expand_tilde(&path),
+        None => {
+            let path = datadir.join(default_filename);
+            // Indexes used to default to the working directory
+            let old_default = Path::new(default_filename);
+            if !path.exists() && old_default.exists() {
+                println!("Note: found {} in the current directory, but indexes now default to {}. \
+                          Move it there or pass --index {}.",
+                         default_filename, path.display(), default_filename);
+            }
+            path
+        }
+    }
+}
+
+// If an index file already exists, ask before a build overwrites it. Returns whether to go ahead.
+fn confirm_overwrite(path: &Path, what: &str) -> anyhow::Result<bool> {
+    if !path.exists() {
+        return Ok(true);
+    }
+
+    let capitalized = what[..1].to_uppercase() + &what[1..];
+    println!("Warning: {} file '{}' already exists.", capitalized, path.display());
+    println!("This will overwrite the existing {}. Continue? (y/N)", what);
+
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+
+    if !input.trim().to_lowercase().starts_with('y') {
+        println!("{} build cancelled.", capitalized);
+        return Ok(false);
+    }
+
+    println!("Overwriting existing {}...", what);
+    Ok(true)
+}
+
+// Load the index and make sure it describes the blk files we are about to read
+fn open_index(index_path: &Path, datadir: &Path, xor_key: &[u8; 8]) -> anyhow::Result<BlockIndex> {
+    let block_index = BlockIndex::load_from_file(index_path)
+        .map_err(|e| anyhow::anyhow!("Could not open index {}: {}\nRun build-index first or pass --index.", index_path.display(), e))?;
+    let metadata = &block_index.metadata;
+
+    match metadata.datadir_fingerprint {
+        Some(fingerprint) if fingerprint != index::datadir_fingerprint(datadir) => {
+            return Err(anyhow::anyhow!(
+                "Index {} was built from a different data directory than {}.\n\
+                 Pass the matching --datadir, point --index at this datadir's index, or rebuild with build-index.",
+                index_path.display(),
+                datadir.display()
+            ));
+        }
+        Some(_) => {}
+        None => println!("Warning: index {} does not record its data directory; rebuild it to enable validation", index_path.display()),
+    }
+
+    if let Some(fingerprint) = metadata.xor_key_fingerprint {
+        if fingerprint != index::xor_key_fingerprint(xor_key) {
+            return Err(anyhow::anyhow!(
+                "Index {} was built with a different block file XOR key than {} uses.\n\
+                 The blk files were likely re-obfuscated or replaced; please rebuild with build-index.",
+                index_path.display(),
+                datadir.join("blocks").join("xor.dat").display()
+            ));
+        }
+    }
+
+    Ok(block_index)
+}
+
 fn load_xor_key(datadir: &PathBuf) -> anyhow::Result<[u8; 8]> {
     let xor_path = datadir.join("blocks").join("xor.dat");
     if xor_path.exists() {
@@ -150,6 +400,274 @@ fn calculate_block_fees(transactions: &[Transaction], height: u32) -> Amount {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ScriptType {
+    P2pk,
+    P2pkh,
+    P2sh,
+    P2wpkh,
+    P2wsh,
+    P2tr,
+    OpReturn,
+    Other,
+}
+
+impl ScriptType {
+    const ALL: [ScriptType; 8] = [
+        ScriptType::P2pk,
+        ScriptType::P2pkh,
+        ScriptType::P2sh,
+        ScriptType::P2wpkh,
+        ScriptType::P2wsh,
+        ScriptType::P2tr,
+        ScriptType::OpReturn,
+        ScriptType::Other,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            ScriptType::P2pk => "p2pk",
+            ScriptType::P2pkh => "p2pkh",
+            ScriptType::P2sh => "p2sh",
+            ScriptType::P2wpkh => "p2wpkh",
+            ScriptType::P2wsh => "p2wsh",
+            ScriptType::P2tr => "p2tr",
+            ScriptType::OpReturn => "op_return",
+            ScriptType::Other => "other",
+        }
+    }
+
+    fn from_script(script: &bitcoin::Script) -> Self {
+        if script.is_p2pkh() {
+            ScriptType::P2pkh
+        } else if script.is_p2sh() {
+            ScriptType::P2sh
+        } else if script.is_p2wpkh() {
+            ScriptType::P2wpkh
+        } else if script.is_p2wsh() {
+            ScriptType::P2wsh
+        } else if script.is_p2tr() {
+            ScriptType::P2tr
+        } else if script.is_p2pk() {
+            ScriptType::P2pk
+        } else if script.is_op_return() {
+            ScriptType::OpReturn
+        } else {
+            ScriptType::Other
+        }
+    }
+}
+
+impl ScriptType {
+    // A scriptPubKey of this type, for code that only inspects an output's template
+    // (the UTXO set keeps types rather than full scripts)
+    fn representative_script(self) -> bitcoin::ScriptBuf {
+        use bitcoin::{ScriptBuf, ScriptHash, WPubkeyHash, WScriptHash};
+
+        match self {
+            ScriptType::P2sh => ScriptBuf::new_p2sh(&ScriptHash::all_zeros()),
+            ScriptType::P2wpkh => ScriptBuf::new_p2wpkh(&WPubkeyHash::all_zeros()),
+            ScriptType::P2wsh => ScriptBuf::new_p2wsh(&WScriptHash::all_zeros()),
+            ScriptType::P2tr => bitcoin::script::Builder::new()
+                .push_opcode(bitcoin::opcodes::all::OP_PUSHNUM_1)
+                .push_slice([0u8; 32])
+                .into_script(),
+            _ => ScriptBuf::new(),
+        }
+    }
+}
+
+// Consensus limit on the total sigops cost of a block
+const MAX_BLOCK_SIGOPS_COST: u64 = 80_000;
+
+// First mainnet height at which Bitcoin Core enforces BIP16 and counts P2SH sigops
+const BIP16_HEIGHT: u32 = 173_805;
+
+// Sigops cost of a transaction as Bitcoin Core's GetTransactionSigOpCost() counts it:
+// legacy sigops in scriptSigs and scriptPubKeys, sigops in P2SH redeem scripts (both
+// scaled by the witness factor) and witness sigops. Before BIP16 only legacy sigops count.
+fn transaction_sigops_cost(tx: &Transaction, height: u32, utxo_set: &UtxoSet) -> u64 {
+    if height < BIP16_HEIGHT {
+        // Without spent outputs only the legacy sigops are counted
+        return tx.total_sigop_cost(|_| None) as u64;
+    }
+
+    tx.total_sigop_cost(|outpoint| {
+        utxo_set.get_entry(&outpoint.txid, outpoint.vout).map(|entry| bitcoin::TxOut {
+            value: Amount::from_sat(entry.value),
+            script_pubkey: entry.script_type.representative_script(),
+        })
+    }) as u64
+}
+
+// Data pushes of a push-only script, or None if it contains any other opcode
+fn script_pushes(script: &bitcoin::Script) -> Option<Vec<&[u8]>> {
+    let mut pushes = Vec::new();
+    for instruction in script.instructions() {
+        match instruction.ok()? {
+            bitcoin::script::Instruction::PushBytes(bytes) => pushes.push(bytes.as_bytes()),
+            bitcoin::script::Instruction::Op(_) => return None,
+        }
+    }
+    Some(pushes)
+}
+
+fn looks_like_pubkey(data: &[u8]) -> bool {
+    (data.len() == 33 && (data[0] == 0x02 || data[0] == 0x03)) || (data.len() == 65 && data[0] == 0x04)
+}
+
+fn looks_like_signature(data: &[u8]) -> bool {
+    (9..=73).contains(&data.len()) && data[0] == 0x30
+}
+
+// Infer the type of the output an input spends from the shape of its scriptSig and witness,
+// and reconstruct that output's scriptPubKey where the spend reveals it
+fn classify_spend(input: &bitcoin::TxIn) -> (ScriptType, Option<bitcoin::ScriptBuf>) {
+    use bitcoin::{PubkeyHash, ScriptBuf, ScriptHash, WPubkeyHash, WScriptHash};
+
+    if input.witness.is_empty() {
+        let pushes = match script_pushes(&input.script_sig) {
+            Some(pushes) => pushes,
+            None => return (ScriptType::Other, None),
+        };
+        return match pushes.last() {
+            Some(&pubkey) if pushes.len() == 2 && looks_like_pubkey(pubkey) => {
+                (ScriptType::P2pkh, Some(ScriptBuf::new_p2pkh(&<PubkeyHash as BitcoinHash>::hash(pubkey))))
+            }
+            Some(&sig) if looks_like_signature(sig) => {
+                // Bare P2PK or bare multisig spends end with a signature
+                let script_type = if pushes.len() == 1 { ScriptType::P2pk } else { ScriptType::Other };
+                (script_type, None)
+            }
+            Some(&redeem_script) if !redeem_script.is_empty() => {
+                (ScriptType::P2sh, Some(ScriptBuf::new_p2sh(&<ScriptHash as BitcoinHash>::hash(redeem_script))))
+            }
+            _ => (ScriptType::Other, None),
+        };
+    }
+
+    // Wrapped segwit: the scriptSig carries the witness program as the redeem script
+    if !input.script_sig.is_empty() {
+        return match script_pushes(&input.script_sig).as_ref().and_then(|pushes| pushes.last()) {
+            Some(&redeem_script) => (ScriptType::P2sh, Some(ScriptBuf::new_p2sh(&<ScriptHash as BitcoinHash>::hash(redeem_script)))),
+            None => (ScriptType::Other, None),
+        };
+    }
+
+    let items: Vec<&[u8]> = input.witness.iter().collect();
+    let last = items[items.len() - 1];
+
+    if items.len() == 2 && last.len() == 33 {
+        return (ScriptType::P2wpkh, Some(ScriptBuf::new_p2wpkh(&<WPubkeyHash as BitcoinHash>::hash(last))));
+    }
+    if items.len() == 1 && (last.len() == 64 || last.len() == 65) {
+        return (ScriptType::P2tr, None); // Taproot key path spend
+    }
+
+    // Taproot script path spends end with a control block (optionally followed by an annex)
+    let control_block = if items.len() >= 2 && last.first() == Some(&0x50) { items[items.len() - 2] } else { last };
+    if control_block.len() >= 33 && (control_block.len() - 33) % 32 == 0 && control_block[0] & 0xfe == 0xc0 {
+        return (ScriptType::P2tr, None);
+    }
+
+    (ScriptType::P2wsh, Some(ScriptBuf::new_p2wsh(&<WScriptHash as BitcoinHash>::hash(last))))
+}
+
+// Outputs whose value is a multiple of this are treated as likely payments
+const ROUND_AMOUNT_SATS: u64 = 10_000;
+
+// Guess which output of a transaction returns change to the sender, using
+// the usual heuristics in order of confidence:
+//   1. exactly one output pays back to a script that one of the inputs spent from
+//   2. all inputs share a script type and exactly one output has that type
+//   3. exactly one output has a non-round amount
+fn likely_change_output(tx: &Transaction) -> Option<usize> {
+    let candidates: Vec<usize> = tx.output.iter()
+        .enumerate()
+        .filter(|(_, output)| !output.script_pubkey.is_op_return())
+        .map(|(idx, _)| idx)
+        .collect();
+
+    if candidates.len() < 2 || tx.input.is_empty() {
+        return None;
+    }
+
+    let spends: Vec<(ScriptType, Option<bitcoin::ScriptBuf>)> = tx.input.iter().map(classify_spend).collect();
+
+    let self_sends: Vec<usize> = candidates.iter()
+        .copied()
+        .filter(|&idx| spends.iter().any(|(_, script)| script.as_ref() == Some(&tx.output[idx].script_pubkey)))
+        .collect();
+    if self_sends.len() == 1 {
+        return Some(self_sends[0]);
+    }
+
+    let input_type = spends[0].0;
+    if input_type != ScriptType::Other && spends.iter().all(|(script_type, _)| *script_type == input_type) {
+        let same_type: Vec<usize> = candidates.iter()
+            .copied()
+            .filter(|&idx| ScriptType::from_script(&tx.output[idx].script_pubkey) == input_type)
+            .collect();
+        if same_type.len() == 1 {
+            return Some(same_type[0]);
+        }
+    }
+
+    let non_round: Vec<usize> = candidates.iter()
+        .copied()
+        .filter(|&idx| !tx.output[idx].value.to_sat().is_multiple_of(ROUND_AMOUNT_SATS))
+        .collect();
+    if non_round.len() == 1 {
+        return Some(non_round[0]);
+    }
+
+    None
+}
+
+// Bitcoin Core's default -dustrelayfee, in sat/kvB
+const DEFAULT_DUST_RELAY_FEE: u64 = 3000;
+
+// Smallest output value Bitcoin Core will relay, following GetDustThreshold():
+// the cost at the dust relay fee rate of creating the output plus later spending it
+fn dust_threshold(output: &bitcoin::TxOut, dust_relay_fee: u64) -> u64 {
+    let script = &output.script_pubkey;
+    if script.is_op_return() || script.len() > 10_000 {
+        return 0; // Unspendable outputs are never dust
+    }
+
+    // Serialized output: 8-byte value, compact size length prefix, script
+    let script_len_prefix = match script.len() {
+        0..=0xfc => 1,
+        0xfd..=0xffff => 3,
+        _ => 5,
+    };
+    let mut spend_size = 8 + script_len_prefix + script.len() as u64;
+
+    if script.is_witness_program() {
+        // Outpoint, empty scriptSig, sequence and a discounted P2WPKH-sized witness
+        spend_size += 32 + 4 + 1 + (107 / 4) + 4;
+    } else {
+        // Outpoint, P2PKH-sized scriptSig and sequence
+        spend_size += 32 + 4 + 1 + 107 + 4;
+    }
+
+    dust_relay_fee * spend_size / 1000
+}
+
+fn is_dust_output(output: &bitcoin::TxOut, dust_relay_fee: u64) -> bool {
+    output.value.to_sat() < dust_threshold(output, dust_relay_fee)
+}
+
+// Count the block's non-coinbase dust outputs, optionally restricted to one script type
+fn count_dust_outputs(ctx: &ColumnContext, script_type: Option<ScriptType>) -> f64 {
+    ctx.block().txdata.iter()
+        .skip(1) // Skip coinbase
+        .flat_map(|tx| tx.output.iter())
+        .filter(|output| script_type.is_none_or(|t| ScriptType::from_script(&output.script_pubkey) == t))
+        .filter(|output| is_dust_output(output, ctx.options.dust_relay_fee))
+        .count() as f64
+}
+
 fn utxo_key(txid: &bitcoin::Txid, output_index: u32) -> u64 {
     let mut hasher = std::collections::hash_map::DefaultHasher::new();
     std::hash::Hash::hash(txid, &mut hasher);
@@ -157,20 +675,171 @@ fn utxo_key(txid: &bitcoin::Txid, output_index: u32) -> u64 {
     hasher.finish()
 }
 
+// UTXO values are binned by order of magnitude: bin k holds [10^k, 10^(k+1)) sats,
+// with zero-value outputs counted in bin 0
+const VALUE_BUCKET_COUNT: usize = 16;
+
+fn value_bucket(value_sats: u64) -> usize {
+    let digits = value_sats.checked_ilog10().unwrap_or(0) as usize;
+    digits.min(VALUE_BUCKET_COUNT - 1)
+}
+
+// Bitcoin Core's CompressAmount(), used to size coins in the chainstate database
+fn compress_amount(mut n: u64) -> u64 {
+    if n == 0 {
+        return 0;
+    }
+    let mut e = 0;
+    while n.is_multiple_of(10) && e < 9 {
+        n /= 10;
+        e += 1;
+    }
+    if e < 9 {
+        let d = n % 10;
+        n /= 10;
+        1 + (n * 9 + d - 1) * 10 + e
+    } else {
+        1 + (n - 1) * 10 + 9
+    }
+}
+
+// Length of Bitcoin Core's VARINT encoding (7 bits per byte)
+fn varint_len(n: u64) -> u32 {
+    (64 - n.leading_zeros()).div_ceil(7).max(1)
+}
+
+// Estimated size of the coin's chainstate record: key ('C', txid, VARINT vout)
+// plus value (VARINT height/coinbase code, compressed amount, compressed script)
+fn coin_serialized_size(output_index: u32, block_height: u32, output: &bitcoin::TxOut, script_type: ScriptType) -> u32 {
+    let script_len = output.script_pubkey.len() as u64;
+    let script_size = match script_type {
+        ScriptType::P2pkh | ScriptType::P2sh => 21,
+        ScriptType::P2pk => 33,
+        _ => varint_len(script_len + 6) + script_len as u32,
+    };
+
+    1 + 32 + varint_len(output_index as u64)
+        + varint_len(block_height as u64 * 2)
+        + varint_len(compress_amount(output.value.to_sat()))
+        + script_size
+}
+
+#[derive(Clone, Copy)]
+struct UtxoEntry {
+    value: u64,
+    height: u32, // height of the block that created the output
+    serialized_size: u32,
+    script_type: ScriptType,
+    is_dust: bool,
+    script_fingerprint: u64, // of the output's scriptPubKey, with --script-reuse (0 otherwise)
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct UtxoTotals {
+    count: u64,
+    value: u64,
+}
+
+impl UtxoTotals {
+    fn add(&mut self, value: u64) {
+        self.count += 1;
+        self.value += value;
+    }
+
+    fn remove(&mut self, value: u64) {
+        self.count -= 1;
+        self.value -= value;
+    }
+}
+
+// Fenwick tree of UTXO value keyed by creation height, so the value created
+// within any height range can be summed in O(log n)
+struct ValueByHeight {
+    tree: Vec<i64>,
+}
+
+impl ValueByHeight {
+    fn new(max_height: u32) -> Self {
+        Self {
+            tree: vec![0; max_height as usize + 2],
+        }
+    }
+
+    fn add(&mut self, height: u32, delta: i64) {
+        let mut idx = height as usize + 1;
+        while idx < self.tree.len() {
+            self.tree[idx] += delta;
+            idx += idx & idx.wrapping_neg();
+        }
+    }
+
+    // Total value created at heights <= height
+    fn prefix_sum(&self, height: u32) -> i64 {
+        let mut idx = (height as usize + 1).min(self.tree.len() - 1);
+        let mut sum = 0;
+        while idx > 0 {
+            sum += self.tree[idx];
+            idx -= idx & idx.wrapping_neg();
+        }
+        sum
+    }
+}
+
+// Age bands for hodl wave columns: (label, upper bound in blocks at ~144 blocks/day).
+// hodl_wave_<label> covers ages from the previous band's bound up to its own;
+// hodl_wave_cum_<label> is cumulative, covering every age below the band's bound.
+// Both describe the UTXO set after the block, so coins it spends have left their
+// old band and coins it creates sit in the youngest one.
+const HODL_WAVE_BANDS: [(&str, u32); 12] = [
+    ("1d", 144),
+    ("1w", 1_008),
+    ("1m", 4_320),
+    ("3m", 12_960),
+    ("6m", 25_920),
+    ("1y", 52_560),
+    ("2y", 105_120),
+    ("3y", 157_680),
+    ("5y", 262_800),
+    ("7y", 367_920),
+    ("10y", 525_600),
+    ("10y_plus", u32::MAX),
+];
+
 struct UtxoSet {
-    active: HashMap<u64, u64>,
+    active: HashMap<u64, UtxoEntry>,
     to_remove: HashSet<u64>,
+    dust_relay_fee: u64,
+    dust_count: usize,
+    total_value: u64,
+    serialized_size: u64,
+    by_script_type: [UtxoTotals; ScriptType::ALL.len()],
+    by_value_bucket: [UtxoTotals; VALUE_BUCKET_COUNT],
+    value_by_height: ValueByHeight,
 }
 
 impl UtxoSet {
-    fn new() -> Self {
+    fn new(dust_relay_fee: u64, max_height: u32) -> Self {
         Self {
             active: HashMap::new(),
             to_remove: HashSet::new(),
+            dust_relay_fee,
+            dust_count: 0,
+            total_value: 0,
+            serialized_size: 0,
+            by_script_type: [UtxoTotals::default(); ScriptType::ALL.len()],
+            by_value_bucket: [UtxoTotals::default(); VALUE_BUCKET_COUNT],
+            value_by_height: ValueByHeight::new(max_height),
         }
     }
 
-    fn add_output(&mut self, txid: &bitcoin::Txid, output_index: u32, value_sats: u64, block_height: u32) -> anyhow::Result<()> {
+    fn add_output(
+        &mut self,
+        txid: &bitcoin::Txid,
+        output_index: u32,
+        output: &bitcoin::TxOut,
+        block_height: u32,
+        script_fingerprint: u64,
+    ) -> anyhow::Result<()> {
         let key = utxo_key(txid, output_index);
 
         // Collision detection - error if key already exists (except blocks with duplicate coinbase)
@@ -181,49 +850,217 @@ impl UtxoSet {
             ));
         }
 
-        self.active.insert(key, value_sats);
+        let script_type = ScriptType::from_script(&output.script_pubkey);
+        let entry = UtxoEntry {
+            value: output.value.to_sat(),
+            height: block_height,
+            serialized_size: coin_serialized_size(output_index, block_height, output, script_type),
+            script_type,
+            is_dust: is_dust_output(output, self.dust_relay_fee),
+            script_fingerprint,
+        };
+        self.account_added(&entry);
+        if let Some(replaced) = self.active.insert(key, entry) {
+            self.account_removed(&replaced);
+        }
         Ok(())
     }
 
+    // Spent entries stay readable until commit_removals so that per-input
+    // columns can still look them up, but the running totals drop them
+    // straight away so that set-level columns describe the set after the block
     fn mark_for_removal(&mut self, txid: &bitcoin::Txid, output_index: u32) {
         let key = utxo_key(txid, output_index);
-        self.to_remove.insert(key);
+        let Some(&entry) = self.active.get(&key) else {
+            return;
+        };
+        if self.to_remove.insert(key) {
+            self.account_removed(&entry);
+        }
     }
 
     fn get_value(&self, txid: &bitcoin::Txid, output_index: u32) -> Option<u64> {
+        self.get_entry(txid, output_index).map(|entry| entry.value)
+    }
+
+    fn get_entry(&self, txid: &bitcoin::Txid, output_index: u32) -> Option<&UtxoEntry> {
         let key = utxo_key(txid, output_index);
-        self.active.get(&key).copied()
+        self.active.get(&key)
     }
 
     fn commit_removals(&mut self) {
-        for key in &self.to_remove {
-            self.active.remove(key);
+        for key in std::mem::take(&mut self.to_remove) {
+            self.active.remove(&key);
+        }
+    }
+
+    // Keep running totals in step with the entries so that per-block
+    // columns never need to scan the whole set
+    fn account_added(&mut self, entry: &UtxoEntry) {
+        if entry.is_dust {
+            self.dust_count += 1;
+        }
+        self.total_value += entry.value;
+        self.serialized_size += entry.serialized_size as u64;
+        self.by_script_type[entry.script_type as usize].add(entry.value);
+        self.by_value_bucket[value_bucket(entry.value)].add(entry.value);
+        self.value_by_height.add(entry.height, entry.value as i64);
+    }
+
+    fn account_removed(&mut self, entry: &UtxoEntry) {
+        if entry.is_dust {
+            self.dust_count -= 1;
         }
-        self.to_remove.clear();
+        self.total_value -= entry.value;
+        self.serialized_size -= entry.serialized_size as u64;
+        self.by_script_type[entry.script_type as usize].remove(entry.value);
+        self.by_value_bucket[value_bucket(entry.value)].remove(entry.value);
+        self.value_by_height.add(entry.height, -(entry.value as i64));
+    }
+
+    // Total value of UTXOs aged within [min_age, max_age) blocks at the given height,
+    // excluding outputs already marked as spent by the current block
+    fn value_aged_between(&self, height: u32, min_age: u32, max_age: u32) -> u64 {
+        if min_age > height {
+            return 0;
+        }
+        let newest = self.value_by_height.prefix_sum(height - min_age);
+        let older = match height.checked_sub(max_age) {
+            Some(oldest_excluded) => self.value_by_height.prefix_sum(oldest_excluded),
+            None => 0,
+        };
+        (newest - older) as u64
     }
 
+    // Entries marked for removal are already spent, so they are not counted
     fn len(&self) -> usize {
-        self.active.len()
+        self.active.len() - self.to_remove.len()
     }
 }
 
-fn build_index(datadir: PathBuf) -> anyhow::Result<()> {
-    println!("Building index from data directory: {}", datadir.display());
+// Blocks per difficulty adjustment period
+const RETARGET_INTERVAL: u32 = 2016;
 
-    // Check if index already exists
-    if std::path::Path::new(INDEX_PATH).exists() {
-        println!("Warning: Index file '{}' already exists.", INDEX_PATH);
-        println!("This will overwrite the existing index. Continue? (y/N)");
+// Approximate a 256-bit big-endian integer (target or work) as a float
+fn u256_to_f64(bytes: [u8; 32]) -> f64 {
+    bytes.iter().fold(0.0, |acc, &byte| acc * 256.0 + byte as f64)
+}
 
-        let mut input = String::new();
-        io::stdin().read_line(&mut input)?;
+// Number of preceding headers whose median timestamp the next block must exceed
+const MEDIAN_TIME_SPAN: usize = 11;
+
+// The most recent headers before the block being exported, for columns that
+// compare a block with its ancestors
+struct HeaderHistory {
+    headers: std::collections::VecDeque<bitcoin::block::Header>,
+    capacity: usize,
+    block_times: Vec<u32>,    // timestamp of every header pushed so far, by height
+    chainwork: bitcoin::Work, // total work of every header pushed so far
+    signals: SignalCounter,   // version bits signaled so far in the latest retarget period
+}
 
-        if !input.trim().to_lowercase().starts_with('y') {
-            println!("Index build cancelled.");
-            return Ok(());
+impl HeaderHistory {
+    fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(MEDIAN_TIME_SPAN);
+        Self {
+            headers: std::collections::VecDeque::with_capacity(capacity),
+            capacity,
+            block_times: Vec::new(),
+            chainwork: bitcoin::Work::from_be_bytes([0; 32]),
+            signals: SignalCounter::new(),
+        }
+    }
+
+    fn push(&mut self, height: u32, header: bitcoin::block::Header) {
+        if self.headers.len() == self.capacity {
+            self.headers.pop_front();
+        }
+        if self.block_times.len() <= height as usize {
+            self.block_times.resize(height as usize + 1, 0);
+        }
+        self.block_times[height as usize] = header.time;
+        self.chainwork = self.chainwork + header.work();
+        self.signals.record(height, header.version.to_consensus(), RETARGET_INTERVAL);
+        self.headers.push_back(header);
+    }
+
+    fn block_time(&self, height: u32) -> Option<u32> {
+        self.block_times.get(height as usize).copied()
+    }
+
+    fn previous(&self) -> Option<&bitcoin::block::Header> {
+        self.headers.back()
+    }
+
+    fn median_time_past(&self) -> Option<u32> {
+        let mut times: Vec<u32> = self.headers.iter()
+            .rev()
+            .take(MEDIAN_TIME_SPAN)
+            .map(|header| header.time)
+            .collect();
+        if times.is_empty() {
+            return None;
+        }
+        times.sort_unstable();
+        Some(times[times.len() / 2])
+    }
+
+    // Expected hashes per second over the last `window` blocks up to and including `current`
+    fn estimated_hashrate(&self, current: &bitcoin::block::Header, window: usize) -> f64 {
+        let span = window.min(self.headers.len());
+        if span == 0 {
+            return 0.0;
+        }
+
+        let start = &self.headers[self.headers.len() - span];
+        let work: f64 = self.headers.iter()
+            .skip(self.headers.len() - span + 1)
+            .chain(std::iter::once(current))
+            .map(|header| u256_to_f64(header.work().to_be_bytes()))
+            .sum();
+        let elapsed = current.time as i64 - start.time as i64;
+
+        if elapsed > 0 {
+            work / elapsed as f64
+        } else {
+            0.0
+        }
+    }
+}
+
+// Every block summary in one block file, with its offset and size, plus whatever a recovering scan skipped
+struct FileScan {
+    blocks: Vec<(BlockSummary, u64, u32)>,
+    skipped: Vec<SkippedRange>,
+}
+
+fn scan_block_file(blk_file: &Path, xor_key: [u8; 8], recover: bool) -> anyhow::Result<FileScan> {
+    let mut reader = BlockFileReader::new_with_xor_key(blk_file, xor_key)?;
+    let mut blocks = Vec::new();
+    let mut skipped = Vec::new();
+
+    loop {
+        let next = if recover {
+            reader.read_next_summary_recovering(&mut skipped)?
+        } else {
+            reader.read_next_summary().map_err(|e| {
+                anyhow::anyhow!("{} in {}. Rerun with --recover to skip damaged data.", e, blk_file.display())
+            })?
+        };
+        match next {
+            Some(block) => blocks.push(block),
+            None => break,
         }
+    }
+
+    Ok(FileScan { blocks, skipped })
+}
+
+fn build_index(datadir: PathBuf, index_path: PathBuf, recover: bool, threads: usize) -> anyhow::Result<()> {
+    println!("Building index from data directory: {}", datadir.display());
 
-        println!("Overwriting existing index...");
+    if !confirm_overwrite(&index_path, "index")? {
+        return Ok(());
     }
 
     // Load XOR key for deobfuscation
@@ -245,22 +1082,66 @@ fn build_index(datadir: PathBuf) -> anyhow::Result<()> {
     blk_files.sort();
     println!("Found {} block files", blk_files.len());
 
-    // First pass: collect all blocks and their prev_hash relationships
-    let mut blocks_by_hash: HashMap<BlockHash, (u64, String, BlockHash, BlockHeight, u32)> = HashMap::new(); // hash -> (offset, file_path, prev_hash, height, block_size)
+    // First pass: collect all blocks, their prev_hash relationships and summaries (header, tx count, weight)
+    let mut blocks_by_hash: HashMap<BlockHash, (u64, String, BlockHash, BlockHeight, u32, BlockSummary)> = HashMap::new(); // hash -> (offset, file_path, prev_hash, height, block_size, summary)
     let mut genesis_hash: Option<BlockHash> = None;
+    let mut skipped: Vec<SkippedRange> = Vec::new();
+
+    // Files are independent, so scan them in parallel. Each worker takes the next unscanned file
+    // and results are stored by file number, then merged in file order so the outcome does not
+    // depend on the thread count.
+    let threads = threads.clamp(1, blk_files.len().max(1));
+    let total_bytes: u64 = blk_files.iter().map(|blk_file| std::fs::metadata(blk_file).map_or(0, |m| m.len())).sum();
+    let next_file = AtomicUsize::new(0);
+    let files_scanned = AtomicUsize::new(0);
+    let bytes_scanned = AtomicU64::new(0);
+    let failed = AtomicBool::new(false);
+    let scans = Mutex::new((0..blk_files.len()).map(|_| None).collect::<Vec<_>>());
+
+    println!("Scanning {} block files ({} MB) with {} threads", blk_files.len(), total_bytes / 1_000_000, threads);
+    std::thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| {
+                // Stop taking new files once any scan has failed
+                while !failed.load(Ordering::Relaxed) {
+                    let file_number = next_file.fetch_add(1, Ordering::Relaxed);
+                    let Some(blk_file) = blk_files.get(file_number) else {
+                        break;
+                    };
+
+                    let scan = scan_block_file(blk_file, xor_key, recover);
+                    match &scan {
+                        Ok(file_scan) => {
+                            let file_bytes = std::fs::metadata(blk_file).map_or(0, |m| m.len());
+                            let files = files_scanned.fetch_add(1, Ordering::Relaxed) + 1;
+                            let bytes = bytes_scanned.fetch_add(file_bytes, Ordering::Relaxed) + file_bytes;
+                            println!("Scanned {} of {} files ({} of {} MB): {} blocks in {}",
+                                     files, blk_files.len(), bytes / 1_000_000, total_bytes / 1_000_000,
+                                     file_scan.blocks.len(), blk_file.display());
+                        }
+                        Err(_) => failed.store(true, Ordering::Relaxed),
+                    }
+                    scans.lock().unwrap()[file_number] = Some(scan);
+                }
+            });
+        }
+    });
 
-    for blk_file in &blk_files {
-        println!("Processing file: {}", blk_file.display());
-        let mut reader = BlockFileReader::new_with_xor_key(blk_file, xor_key)?;
-        let mut block_count = 0;
+    // Merge in file order; the first failed file (if any) is reported
+    for (blk_file, scan) in blk_files.iter().zip(scans.into_inner().unwrap()) {
+        let Some(scan) = scan else {
+            continue; // not scanned because another file failed first
+        };
+        let FileScan { blocks, skipped: file_skipped } = scan?;
+        let file_path = blk_file.to_string_lossy().to_string();
 
-        while let Some((header, offset, block_size)) = reader.read_next_header()? {
-            let block_hash = header.block_hash();
-            let prev_hash = header.prev_blockhash;
+        for (summary, offset, block_size) in blocks {
+            let block_hash = summary.header.block_hash();
+            let prev_hash = summary.header.prev_blockhash;
 
             blocks_by_hash.insert(
                 block_hash,
-                (offset, blk_file.to_string_lossy().to_string(), prev_hash, BlockHeight::NotYetKnown, block_size)
+                (offset, file_path.clone(), prev_hash, BlockHeight::NotYetKnown, block_size, summary)
             );
 
             // Check if this is the genesis block (prev_hash is all zeros)
@@ -268,19 +1149,20 @@ fn build_index(datadir: PathBuf) -> anyhow::Result<()> {
                 genesis_hash = Some(block_hash);
                 println!("Found genesis block: {}", block_hash);
             }
-
-            block_count += 1;
         }
-
-        println!("  Found {} blocks", block_count);
+        skipped.extend(file_skipped);
     }
 
     println!("Total blocks collected: {}", blocks_by_hash.len());
 
+    if recover {
+        print_scan_report(&skipped);
+    }
+
     // Verify genesis block was found and set its height to 0
-    let _genesis = match genesis_hash {
+    let genesis = match genesis_hash {
         Some(hash) => {
-            if let Some((_, _, _, height, _)) = blocks_by_hash.get_mut(&hash) {
+            if let Some((_, _, _, height, _, _)) = blocks_by_hash.get_mut(&hash) {
                 *height = BlockHeight::Known(0);
                 println!("Set genesis block height to 0: {}", hash);
             }
@@ -294,10 +1176,10 @@ fn build_index(datadir: PathBuf) -> anyhow::Result<()> {
     // 2. Unwind stack forwards, setting heights incrementally
     fn get_block_height(
         start_hash: &BlockHash,
-        blocks_map: &mut HashMap<BlockHash, (u64, String, BlockHash, BlockHeight, u32)>
+        blocks_map: &mut HashMap<BlockHash, (u64, String, BlockHash, BlockHeight, u32, BlockSummary)>
     ) -> anyhow::Result<BlockHeight> {
         // Check if height is already calculated
-        if let Some((_, _, _, height, _)) = blocks_map.get(start_hash) {
+        if let Some((_, _, _, height, _, _)) = blocks_map.get(start_hash) {
             match height {
                 BlockHeight::Known(_) | BlockHeight::Orphaned => return Ok(*height),
                 BlockHeight::NotYetKnown => {} // Continue to calculate
@@ -313,7 +1195,7 @@ fn build_index(datadir: PathBuf) -> anyhow::Result<()> {
             stack.push(current_hash);
 
             // Check if current block exists and get its info
-            let (_, _, prev_hash, height, _) = match blocks_map.get(&current_hash) {
+            let (_, _, prev_hash, height, _, _) = match blocks_map.get(&current_hash) {
                 Some(info) => info.clone(),
                 None => {
                     // Block not found - entire chain is orphaned
@@ -374,7 +1256,7 @@ fn build_index(datadir: PathBuf) -> anyhow::Result<()> {
 
     // Find the tip block (highest height)
     let tip_height = blocks_by_hash.values()
-        .filter_map(|(_, _, _, height, _)| match height {
+        .filter_map(|(_, _, _, height, _, _)| match height {
             BlockHeight::Known(h) => Some(*h),
             _ => None,
         })
@@ -383,7 +1265,7 @@ fn build_index(datadir: PathBuf) -> anyhow::Result<()> {
     let tip_hash = if let Some(max_height) = tip_height {
         // Find all blocks at tip height
         let tip_blocks: Vec<BlockHash> = blocks_by_hash.iter()
-            .filter_map(|(hash, (_, _, _, height, _))| {
+            .filter_map(|(hash, (_, _, _, height, _, _))| {
                 match height {
                     BlockHeight::Known(h) if *h == max_height => Some(*hash),
                     _ => None,
@@ -410,7 +1292,16 @@ fn build_index(datadir: PathBuf) -> anyhow::Result<()> {
 
     // Build the blockchain index starting from tip and working backwards
     println!("Building blockchain index from tip...");
-    let mut block_index = BlockIndex::new();
+    let metadata = IndexMetadata {
+        network: index::network_from_genesis(genesis),
+        datadir_fingerprint: Some(index::datadir_fingerprint(&datadir)),
+        xor_key_fingerprint: Some(index::xor_key_fingerprint(&xor_key)),
+    };
+    match metadata.network {
+        Some(network) => println!("Detected network: {}", network),
+        None => println!("Warning: genesis block {} does not match any known network", genesis),
+    }
+    let mut block_index = BlockIndexBuilder::new(metadata);
 
     let mut current_hash = tip_hash;
     let mut current_height = match blocks_by_hash.get(&current_hash).unwrap().3 {
@@ -420,12 +1311,13 @@ fn build_index(datadir: PathBuf) -> anyhow::Result<()> {
 
     // Build index by following the chain backwards from tip to genesis
     loop {
-        if let Some((offset, file_path, prev_hash, _, block_size)) = blocks_by_hash.get(&current_hash) {
+        if let Some((offset, file_path, prev_hash, _, block_size, summary)) = blocks_by_hash.get(&current_hash) {
             let location = BlockLocation {
                 file_path: file_path.clone(),
                 file_offset: *offset,
                 block_hash: current_hash,
                 block_size: *block_size,
+                summary: Some(*summary),
             };
 
             block_index.add_block(current_height, location);
@@ -447,55 +1339,469 @@ fn build_index(datadir: PathBuf) -> anyhow::Result<()> {
         }
     }
 
-    println!("Built index for {} blocks", block_index.blocks.len());
+    println!("Built index for {} blocks", block_index.len());
 
     // Save index to file
-    block_index.save_to_file(INDEX_PATH)?;
-    println!("Index saved to: {}", INDEX_PATH);
+    block_index.save_to_file(&index_path)?;
+    println!("Index saved to: {}", index_path.display());
 
     Ok(())
 }
 
-fn iterate_blocks(datadir: PathBuf, start_height: Option<u32>, end_height: Option<u32>) -> anyhow::Result<()> {
-    // Load the index
-    let block_index = BlockIndex::load_from_file(INDEX_PATH)?;
+fn print_scan_report(skipped: &[SkippedRange]) {
+    if skipped.is_empty() {
+        println!("Scan report: no damaged data found");
+        return;
+    }
 
-    // Load XOR key for deobfuscation
-    let xor_key = load_xor_key(&datadir)?;
+    let total_bytes: u64 = skipped.iter().map(|range| range.end - range.start).sum();
+    println!("Scan report: skipped {} ranges ({} bytes)", skipped.len(), total_bytes);
+    for range in skipped {
+        println!("  {} bytes {}..{}: {}", range.file_path, range.start, range.end, range.reason);
+    }
+}
 
-    let start = start_height.unwrap_or(block_index.tip_height);
-    let end = end_height.unwrap_or(0);
+// Describe the index as it is stored, without migrating it
+fn print_index_info(index_path: &Path, datadir: &Path) -> anyhow::Result<()> {
+    let block_index = match BlockIndex::inspect(index_path)? {
+        StoredIndex::Legacy { block_count, tip_height } => {
+            println!("Index file:          {}", index_path.display());
+            println!("Format version:      legacy (bincode)");
+            println!("Blocks:              {}", block_count);
+            println!("Tip height:          {}", tip_height);
+            println!("The next command that opens this index will migrate it to format version {}. The migrated index \
+                      has no network, fingerprints or block headers; rebuild it with build-index to record them.",
+                     index::INDEX_VERSION);
+            return Ok(());
+        }
+        StoredIndex::Compact(block_index) => block_index,
+    };
+    let metadata = &block_index.metadata;
+    let fingerprint = |value: Option<u64>| value.map_or("unknown".to_string(), |value| format!("{:016x}", value));
+
+    println!("Index file:          {}", index_path.display());
+    println!("Format version:      {}", block_index.version);
+    println!("Network:             {}", metadata.network.map_or("unknown".to_string(), |network| network.to_string()));
+    println!("Datadir fingerprint: {}", fingerprint(metadata.datadir_fingerprint));
+    println!("Matches {}: {}", datadir.display(), match metadata.datadir_fingerprint {
+        Some(value) if value == index::datadir_fingerprint(datadir) => "yes",
+        Some(_) => "no",
+        None => "unknown",
+    });
+    println!("XOR key fingerprint: {}", fingerprint(metadata.xor_key_fingerprint));
+    println!("Block files:         {}", block_index.file_count());
+    println!("Blocks:              {}", block_index.len());
+    println!("Tip height:          {}", block_index.tip_height);
+    if let Some(tip) = block_index.get_block_location(block_index.tip_height) {
+        println!("Tip hash:            {}", tip.block_hash);
+    }
+    println!("Block summaries:     {}", if block_index.has_summaries { "yes" } else { "no" });
 
-    println!("Iterating blocks from height {} to {} (reverse order)", start, end);
-    println!("Index contains {} blocks, tip height: {}", block_index.blocks.len(), block_index.tip_height);
+    Ok(())
+}
+
+fn build_txindex(datadir: PathBuf, index_path: PathBuf, txindex_path: PathBuf) -> anyhow::Result<()> {
+    if !confirm_overwrite(&txindex_path, "transaction index")? {
+        return Ok(());
+    }
+
+    let xor_key = load_xor_key(&datadir)?;
+    let block_index = open_index(&index_path, &datadir, &xor_key)?;
+    let tip_height = block_index.tip_height;
+    let tip_hash = block_index.get_block_location(tip_height)
+        .ok_or_else(|| anyhow::anyhow!("Index has no block at its tip height {}", tip_height))?
+        .block_hash;
+
+    println!("Building transaction index for heights 0 to {}", tip_height);
+    let mut builder = TxIndexBuilder::new(&txindex_path)?;
+
+    let mut reader = MappedBlockReader::new(xor_key);
+    for height in 0..=tip_height {
+        let location = block_index.get_block_location(height)
+            .ok_or_else(|| anyhow::anyhow!("Index has no block at height {}", height))?;
+        let block = reader.read_block_at(height, &location, false)?;
+
+        // Transactions follow the 80-byte header and the transaction count
+        let mut tx_offset = 80 + bitcoin::consensus::encode::VarInt(block.txdata.len() as u64).size();
+        for (position, tx) in block.txdata.iter().enumerate() {
+            builder.add(&tx.txid(), TxPosition {
+                height,
+                position: position as u32,
+                tx_offset: tx_offset as u32,
+            })?;
+            tx_offset += tx.total_size();
+        }
+
+        if height % 10000 == 0 {
+            println!("Indexed blocks up to height {} ({} transactions)", height, builder.len());
+        }
+    }
+
+    println!("Sorting {} transactions...", builder.len());
+    builder.finish(&txindex_path, tip_height, tip_hash)?;
+    println!("Transaction index saved to: {}", txindex_path.display());
+
+    Ok(())
+}
+
+// Transaction and script indexes record the tip they were built up to, which must
+// still be on the block index's chain
+fn check_index_tip(
+    block_index: &BlockIndex,
+    index_path: &Path,
+    path: &Path,
+    tip_height: u32,
+    tip_hash: BlockHash,
+    rebuild_command: &str,
+) -> anyhow::Result<()> {
+    let indexed_tip = block_index.get_block_location(tip_height).map(|location| location.block_hash);
+    if indexed_tip != Some(tip_hash) {
+        return Err(anyhow::anyhow!(
+            "Index {} was built against a different chain than {} (block {} at height {}).\n\
+             Please rebuild it with {}.",
+            path.display(), index_path.display(), tip_hash, tip_height, rebuild_command
+        ));
+    }
+    if tip_height < block_index.tip_height {
+        println!("Note: {} only covers heights up to {} (block index tip: {})",
+                 path.display(), tip_height, block_index.tip_height);
+    }
+    Ok(())
+}
+
+// Find a transaction through the transaction index, confirming the txid of each candidate
+// since the index only stores txid prefixes
+fn find_transaction(
+    block_index: &BlockIndex,
+    txindex: &TxIndex,
+    xor_key: [u8; 8],
+    txid: &bitcoin::Txid,
+) -> anyhow::Result<Option<(Transaction, TxPosition)>> {
+    for position in txindex.candidates(txid) {
+        let location = block_index.get_block_location(position.height)
+            .ok_or_else(|| anyhow::anyhow!("Transaction index points at height {}, which is not in the block index", position.height))?;
+        let mut reader = BlockFileReader::new_with_xor_key(&location.file_path, xor_key)?;
+        let tx = reader.read_transaction(location.file_offset, location.block_size, position.tx_offset)?;
+        if tx.txid() == *txid {
+            return Ok(Some((tx, position)));
+        }
+    }
+    Ok(None)
+}
+
+fn describe_script(script: &bitcoin::Script, network: bitcoin::Network) -> String {
+    match bitcoin::Address::from_script(script, network) {
+        Ok(address) => address.to_string(),
+        Err(_) => ScriptType::from_script(script).name().to_string(),
+    }
+}
+
+fn show_transaction(datadir: PathBuf, index_path: PathBuf, txindex_path: PathBuf, txid: bitcoin::Txid) -> anyhow::Result<()> {
+    let xor_key = load_xor_key(&datadir)?;
+    let block_index = open_index(&index_path, &datadir, &xor_key)?;
+    let txindex = TxIndex::load_from_file(&txindex_path)
+        .map_err(|e| anyhow::anyhow!("Could not open transaction index {}: {}\nRun build-txindex first or pass --txindex.", txindex_path.display(), e))?;
+
+    check_index_tip(&block_index, &index_path, &txindex_path, txindex.tip_height, txindex.tip_hash, "build-txindex")?;
+
+    let (tx, position) = find_transaction(&block_index, &txindex, xor_key, &txid)?
+        .ok_or_else(|| anyhow::anyhow!("Transaction {} not found in {}", txid, txindex_path.display()))?;
+    let location = block_index.get_block_location(position.height)
+        .ok_or_else(|| anyhow::anyhow!("Block index has no block at height {}", position.height))?;
+    let network = block_index.metadata.network.unwrap_or(bitcoin::Network::Bitcoin);
+
+    println!("Transaction {}", txid);
+    println!("  wtxid:     {}", tx.wtxid());
+    println!("  Block:     {} (height {}, position {})", location.block_hash, position.height, position.position);
+    if let Some(summary) = &location.summary {
+        println!("  Time:      {}", summary.header.time);
+    }
+    println!("  Version:   {}", tx.version.0);
+    println!("  Lock time: {}", tx.lock_time);
+    println!("  Size:      {} bytes, {} vbytes, {} weight units", tx.total_size(), tx.vsize(), tx.weight().to_wu());
+
+    // Prevouts come from the transaction index, so the fee is known whenever every parent is indexed
+    let mut input_value = Some(0u64);
+    println!("  Inputs ({}):", tx.input.len());
+    for (index, input) in tx.input.iter().enumerate() {
+        if tx.is_coinbase() {
+            println!("    {}: coinbase", index);
+            input_value = None;
+            continue;
+        }
+
+        let prevout = find_transaction(&block_index, &txindex, xor_key, &input.previous_output.txid)?
+            .and_then(|(parent, _)| parent.output.get(input.previous_output.vout as usize).cloned());
+        match prevout {
+            Some(output) => {
+                println!("    {}: {} {} {}", index, input.previous_output, output.value,
+                         describe_script(&output.script_pubkey, network));
+                input_value = input_value.map(|value| value + output.value.to_sat());
+            }
+            None => {
+                println!("    {}: {} (previous output not in transaction index)", index, input.previous_output);
+                input_value = None;
+            }
+        }
+    }
+
+    println!("  Outputs ({}):", tx.output.len());
+    for (index, output) in tx.output.iter().enumerate() {
+        println!("    {}: {} {}", index, output.value, describe_script(&output.script_pubkey, network));
+    }
+
+    let output_value: u64 = tx.output.iter().map(|output| output.value.to_sat()).sum();
+    match input_value {
+        Some(input_value) => {
+            let fee = input_value.saturating_sub(output_value);
+            println!("  Fee:       {} ({:.2} sat/vB)", Amount::from_sat(fee), fee as f64 / tx.vsize() as f64);
+        }
+        None if tx.is_coinbase() => println!("  Fee:       none (coinbase)"),
+        None => println!("  Fee:       unknown"),
+    }
+
+    Ok(())
+}
+
+fn build_scriptindex(datadir: PathBuf, index_path: PathBuf, scriptindex_path: PathBuf) -> anyhow::Result<()> {
+    if !confirm_overwrite(&scriptindex_path, "script index")? {
+        return Ok(());
+    }
+
+    let xor_key = load_xor_key(&datadir)?;
+    let block_index = open_index(&index_path, &datadir, &xor_key)?;
+    let tip_height = block_index.tip_height;
+    let tip_hash = block_index.get_block_location(tip_height)
+        .ok_or_else(|| anyhow::anyhow!("Index has no block at its tip height {}", tip_height))?
+        .block_hash;
+
+    println!("Building script index for heights 0 to {}", tip_height);
+    let mut builder = ScriptIndexBuilder::new(&scriptindex_path)?;
+
+    // Spends are attributed to their script once every output has been seen
+    let mut resolver = SpendResolver::new(&scriptindex_path)?;
+
+    let mut reader = MappedBlockReader::new(xor_key);
+    for height in 0..=tip_height {
+        let location = block_index.get_block_location(height)
+            .ok_or_else(|| anyhow::anyhow!("Index has no block at height {}", height))?;
+        let block = reader.read_block_at(height, &location, false)?;
+
+        for (position, tx) in block.txdata.iter().enumerate() {
+            let txid = tx.txid();
+
+            if !tx.is_coinbase() {
+                for (input_index, input) in tx.input.iter().enumerate() {
+                    resolver.add_spend(&input.previous_output, height, position as u32, input_index as u32, &txid)?;
+                }
+            }
+
+            for (output_index, output) in tx.output.iter().enumerate() {
+                // Skip OP_RETURN outputs (provably unspendable)
+                if output.script_pubkey.is_op_return() {
+                    continue;
+                }
+                let script_hash = scriptindex::script_hash(&output.script_pubkey);
+                let value = output.value.to_sat();
+                builder.add(&script_hash, &ScriptHistoryEntry {
+                    height,
+                    position: position as u32,
+                    direction: Direction::Received,
+                    index: output_index as u32,
+                    txid,
+                    value,
+                })?;
+                resolver.add_output(&bitcoin::OutPoint::new(txid, output_index as u32), &script_hash, value)?;
+            }
+        }
+
+        if height % 10000 == 0 {
+            println!("Indexed blocks up to height {} ({} entries)", height, builder.len());
+        }
+    }
+
+    println!("Matching spends to the outputs they spend...");
+    let spends = resolver.resolve(&mut builder)?;
+    println!("Attributed {} spends", spends);
+
+    println!("Sorting {} entries...", builder.len());
+    builder.finish(&scriptindex_path, tip_height, tip_hash)?;
+    println!("Script index saved to: {}", scriptindex_path.display());
+
+    Ok(())
+}
+
+fn show_address_history(
+    datadir: PathBuf,
+    index_path: PathBuf,
+    scriptindex_path: PathBuf,
+    address: &str,
+    output: Option<PathBuf>,
+) -> anyhow::Result<()> {
+    let xor_key = load_xor_key(&datadir)?;
+    let block_index = open_index(&index_path, &datadir, &xor_key)?;
+    let scriptindex = ScriptIndex::load_from_file(&scriptindex_path)
+        .map_err(|e| anyhow::anyhow!("Could not open script index {}: {}\nRun build-scriptindex first or pass --scriptindex.", scriptindex_path.display(), e))?;
+    check_index_tip(&block_index, &index_path, &scriptindex_path, scriptindex.tip_height, scriptindex.tip_hash, "build-scriptindex")?;
+
+    let network = block_index.metadata.network.unwrap_or(bitcoin::Network::Bitcoin);
+    let script = match address.parse::<bitcoin::Address<bitcoin::address::NetworkUnchecked>>() {
+        Ok(parsed) => parsed.require_network(network)?.script_pubkey(),
+        Err(_) => bitcoin::ScriptBuf::from_hex(address)
+            .map_err(|_| anyhow::anyhow!("'{}' is neither a {} address nor a hex scriptPubKey", address, network))?,
+    };
+
+    let history = scriptindex.history(&script);
+    println!("History for {} ({} entries)", address, history.len());
+
+    let mut balance = 0i64;
+    let mut balances = Vec::with_capacity(history.len());
+    for entry in &history {
+        let (label, delta) = match entry.direction {
+            Direction::Received => ("received", entry.value as i64),
+            Direction::Spent => ("spent", -(entry.value as i64)),
+        };
+        balance += delta;
+        balances.push(balance);
+        println!("  height {:>7}  {}:{:<4} {:>8} {:>16} sat  balance {:>16} sat",
+                 entry.height, entry.txid, entry.index, label, entry.value, balance);
+    }
+
+    let received: Vec<_> = history.iter().filter(|entry| entry.direction == Direction::Received).collect();
+    let spent: Vec<_> = history.iter().filter(|entry| entry.direction == Direction::Spent).collect();
+    println!("Received: {} in {} outputs", Amount::from_sat(received.iter().map(|entry| entry.value).sum()), received.len());
+    println!("Spent:    {} in {} inputs", Amount::from_sat(spent.iter().map(|entry| entry.value).sum()), spent.len());
+    println!("Balance:  {}", SignedAmount::from_sat(balance));
+    if balances.iter().any(|&balance| balance < 0) {
+        // Only possible if another script shares this one's truncated script hash
+        println!("Warning: the balance went negative, so this history includes entries for another script \
+                  with the same {}-byte script hash prefix", scriptindex::SCRIPT_HASH_SIZE);
+    }
+    if let (Some(first), Some(last)) = (history.first(), history.last()) {
+        println!("Active from height {} to {}", first.height, last.height);
+    }
+
+    if let Some(filename) = output {
+        use arrow::array::{Float64Array, RecordBatch, RecordBatchWriter, StringArray};
+        use arrow::datatypes::{DataType, Field, Schema};
+        use arrow_ipc::writer::FileWriter;
+
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("height", DataType::Float64, false),
+            Field::new("txid", DataType::Utf8, false),
+            Field::new("index", DataType::Float64, false),
+            Field::new("direction", DataType::Float64, false), // 1 received, -1 spent
+            Field::new("value", DataType::Float64, false),
+            Field::new("balance", DataType::Float64, false),
+        ]));
+        let arrays: Vec<Arc<dyn arrow::array::Array>> = vec![
+            Arc::new(Float64Array::from_iter_values(history.iter().map(|entry| entry.height as f64))),
+            Arc::new(StringArray::from_iter_values(history.iter().map(|entry| entry.txid.to_string()))),
+            Arc::new(Float64Array::from_iter_values(history.iter().map(|entry| entry.index as f64))),
+            Arc::new(Float64Array::from_iter_values(history.iter().map(|entry| match entry.direction {
+                Direction::Received => 1.0,
+                Direction::Spent => -1.0,
+            }))),
+            Arc::new(Float64Array::from_iter_values(history.iter().map(|entry| entry.value as f64))),
+            Arc::new(Float64Array::from_iter_values(balances.iter().map(|&balance| balance as f64))),
+        ];
+        let batch = RecordBatch::try_new(schema.clone(), arrays)?;
+
+        let file = std::fs::File::create(&filename)?;
+        let mut writer = FileWriter::try_new(file, &schema)?;
+        writer.write(&batch)?;
+        writer.close()?;
+
+        println!("Exported {} rows to {}", history.len(), filename.display());
+    }
+
+    Ok(())
+}
+
+fn verify_block_index(datadir: PathBuf, index_path: PathBuf, merkle: bool, repair: bool, threads: usize) -> anyhow::Result<()> {
+    let xor_key = load_xor_key(&datadir)?;
+    let block_index = open_index(&index_path, &datadir, &xor_key)?;
 
+    println!("Verifying {} blocks in {} with {} threads{}", block_index.len(), index_path.display(), threads,
+             if merkle { ", including merkle roots" } else { "" });
+    let problems = verify::verify_index(&block_index, xor_key, merkle, threads);
+
+    if problems.is_empty() {
+        println!("Index is consistent with the block files");
+        return Ok(());
+    }
+
+    println!("Found {} problems:", problems.len());
+    for problem in problems.iter().take(50) {
+        println!("  height {}: {} ({})", problem.height, problem.description,
+                 problem.file_path.as_deref().unwrap_or("no file"));
+    }
+    if problems.len() > 50 {
+        println!("  ... and {} more", problems.len() - 50);
+    }
+
+    if !repair {
+        return Err(anyhow::anyhow!("Index {} has {} problems; rerun with --repair or rebuild it with build-index",
+                                   index_path.display(), problems.len()));
+    }
+
+    let (builder, unrepaired) = verify::repair_index(&block_index, &problems, xor_key)?;
+    drop(block_index);
+    builder.save_to_file(&index_path)?;
+    println!("Repaired {} of {} problems, index saved to: {}", problems.len() - unrepaired.len(), problems.len(), index_path.display());
+
+    if !unrepaired.is_empty() {
+        return Err(anyhow::anyhow!("{} problems could not be repaired by rescanning and their entries were removed (first at height {}); \
+                                    rebuild the index with build-index",
+                                   unrepaired.len(), unrepaired[0]));
+    }
+    println!("Run verify-index again to confirm the repaired entries");
+
+    Ok(())
+}
+
+fn iterate_blocks(
+    datadir: PathBuf,
+    index_path: PathBuf,
+    start_height: Option<u32>,
+    end_height: Option<u32>,
+    check_merkle: bool,
+) -> anyhow::Result<()> {
+    // Load XOR key for deobfuscation
+    let xor_key = load_xor_key(&datadir)?;
+
+    // Load the index
+    let block_index = open_index(&index_path, &datadir, &xor_key)?;
+
+    let start = start_height.unwrap_or(block_index.tip_height);
+    let end = end_height.unwrap_or(0);
+
+    println!("Iterating blocks from height {} to {} (reverse order)", start, end);
+    println!("Index contains {} blocks, tip height: {}", block_index.len(), block_index.tip_height);
+
+    let mut reader = MappedBlockReader::new(xor_key);
     let mut processed_count = 0;
 
     for (height, location) in block_index.iter_reverse() {
         // Skip blocks outside our range
-        if *height > start || *height < end {
+        if height > start || height < end {
             continue;
         }
 
         // Read the block from file
-        let mut reader = BlockFileReader::new_with_xor_key(&location.file_path, xor_key)?;
-        reader.seek_to_offset(location.file_offset)?;
+        let block = reader.read_block_at(height, &location, check_merkle)?;
 
-        if let Some((block, _offset)) = reader.read_next_block()? {
-            let tx_count = block.txdata.len();
-            let fees = calculate_block_fees(&block.txdata, *height);
-            let fees_btc = fees.to_btc();
+        let tx_count = block.txdata.len();
+        let fees = calculate_block_fees(&block.txdata, height);
+        let fees_btc = fees.to_btc();
 
-            println!("Height: {}, Transactions: {}, Fees: {:.8} BTC", height, tx_count, fees_btc);
+        println!("Height: {}, Transactions: {}, Fees: {:.8} BTC", height, tx_count, fees_btc);
 
-            processed_count += 1;
+        processed_count += 1;
 
-            // Optional: limit output for very large ranges
-            if processed_count % 1000 == 0 {
-                println!("  ... processed {} blocks", processed_count);
-            }
-        } else {
-            eprintln!("Warning: Could not read block at height {}", height);
+        // Optional: limit output for very large ranges
+        if processed_count % 1000 == 0 {
+            println!("  ... processed {} blocks", processed_count);
         }
     }
 
@@ -503,17 +1809,80 @@ fn iterate_blocks(datadir: PathBuf, start_height: Option<u32>, end_height: Optio
     Ok(())
 }
 
+// Tunable parameters for export columns and the optional tracking behind them
+#[derive(Debug, Clone)]
+struct ExportOptions {
+    consolidation_min_inputs: usize,
+    consolidation_max_outputs: usize,
+    batch_max_inputs: usize,
+    batch_min_outputs: usize,
+    dust_relay_fee: u64,
+    hashrate_window: usize,
+    network: Option<bitcoin::Network>,    // --network, checked against the index's network
+    deployments_file: Option<PathBuf>,    // --deployments, otherwise the built-in table
+    deployments: Vec<Deployment>,         // for the resolved network
+    script_reuse: bool,
+    script_filter_mb: usize,
+    check_merkle: bool,
+}
+
+// Everything an extractor may look at for the block being exported
+struct ColumnContext<'a> {
+    location: &'a BlockLocation,
+    header: Option<&'a bitcoin::block::Header>, // None when only an index without summaries was consulted
+    block: Option<&'a bitcoin::Block>,          // None when at most the header was read
+    height: u32,
+    utxo: Option<&'a UtxoSet>,
+    reused_outputs: Option<&'a [Vec<bool>]>, // per transaction and output, with --script-reuse
+    reused_scripts: Option<&'a SeenScripts>, // scripts paid more than once so far, with --script-reuse
+    history: &'a HeaderHistory,
+    options: &'a ExportOptions,
+}
+
+impl ColumnContext<'_> {
+    fn header(&self) -> &bitcoin::block::Header {
+        self.header.expect("column requires the block header - this should have been caught by column_data_requirement")
+    }
+
+    fn block(&self) -> &bitcoin::Block {
+        self.block.expect("column requires full block data - this should have been caught by column_data_requirement")
+    }
+
+    // Served by the full block when it was read, otherwise by the index's block summary
+    fn summary(&self) -> &BlockSummary {
+        self.location.summary.as_ref().expect("column requires a block summary - this should have been caught by column_data_requirement")
+    }
+
+    fn tx_count(&self) -> usize {
+        match self.block {
+            Some(block) => block.txdata.len(),
+            None => self.summary().tx_count as usize,
+        }
+    }
+
+    fn block_weight(&self) -> u64 {
+        match self.block {
+            Some(block) => block.weight().to_wu(),
+            None => self.summary().weight as u64,
+        }
+    }
+}
+
 // Column extraction functions
-type ColumnExtractor = fn(&bitcoin::Block, u32, Option<&UtxoSet>) -> f64;
-type MultiColumnExtractor = fn(&bitcoin::Block, u32, Option<&UtxoSet>) -> Vec<f64>;
+type ColumnExtractor = fn(&ColumnContext) -> f64;
+type MultiColumnExtractor = fn(&ColumnContext) -> Vec<f64>;
+type WeightedMultiColumnExtractor = fn(&ColumnContext) -> Vec<(f64, f64)>;
+type IndexedColumnExtractor = fn(&ColumnContext, usize) -> f64;
 
 #[derive(Debug, Clone)]
 enum ColumnSpec {
     Single(String, ColumnExtractor),
     Multi(String, Vec<f64>, MultiColumnExtractor), // base_name, quantiles, extractor
+    WeightedMulti(String, Vec<f64>, WeightedMultiColumnExtractor), // base_name, quantiles, extractor of (value, weight) pairs
+    Indexed(String, usize, IndexedColumnExtractor), // name, parameter parsed from the name, extractor
 }
 
-fn parse_column_spec(column_input: &str) -> anyhow::Result<ColumnSpec> {
+fn parse_column_spec(column_input: &str, options: &ExportOptions) -> anyhow::Result<ColumnSpec> {
     // Check for quantile syntax: name[q1,q2,q3]
     if let Some(bracket_start) = column_input.find('[') {
         if !column_input.ends_with(']') {
@@ -538,8 +1907,15 @@ fn parse_column_spec(column_input: &str) -> anyhow::Result<ColumnSpec> {
             }
         }
 
+        if let Some(extractor) = get_weighted_multi_column_extractor(&base_name) {
+            return Ok(ColumnSpec::WeightedMulti(base_name, quantiles, extractor));
+        }
+
         let extractor = get_multi_column_extractor(&base_name)?;
         Ok(ColumnSpec::Multi(base_name, quantiles, extractor))
+    } else if let Some((index, extractor)) = get_indexed_column_extractor(column_input, options)? {
+        // Column family whose member is chosen by a suffix, e.g. version_bit_1
+        Ok(ColumnSpec::Indexed(column_input.to_string(), index, extractor))
     } else {
         // Regular single column
         let extractor = get_column_extractor(column_input)?;
@@ -549,14 +1925,45 @@ fn parse_column_spec(column_input: &str) -> anyhow::Result<ColumnSpec> {
 
 fn get_column_extractor(column_name: &str) -> anyhow::Result<ColumnExtractor> {
     match column_name {
-        "height" => Ok(|_block, height, _utxo| height as f64),
-        "timestamp" => Ok(|block, _height, _utxo| block.header.time as f64),
-        "tx_count" => Ok(|block, _height, _utxo| block.txdata.len() as f64),
-        "fee_avg" => Ok(|block, height, _utxo| {
-            let fees = calculate_block_fees(&block.txdata, height);
+        "height" => Ok(|ctx| ctx.height as f64),
+        "timestamp" => Ok(|ctx| ctx.header().time as f64),
+        "tx_count" => Ok(|ctx| ctx.tx_count() as f64),
+        "block_weight" => Ok(|ctx| ctx.block_weight() as f64),
+        "block_interval" => Ok(|ctx| {
+            // Seconds since the previous block's timestamp (negative if it went backwards)
+            match ctx.history.previous() {
+                Some(previous) => ctx.header().time as f64 - previous.time as f64,
+                None => 0.0,
+            }
+        }),
+        "median_time_past" => Ok(|ctx| {
+            // Median timestamp of the previous 11 blocks, which this block's timestamp must exceed
+            ctx.history.median_time_past().unwrap_or(0) as f64
+        }),
+        "backwards_time_delta" => Ok(|ctx| {
+            // Seconds by which the timestamp precedes the previous block's, 0 if it moved forwards
+            match ctx.history.previous() {
+                Some(previous) if ctx.header().time < previous.time => (previous.time - ctx.header().time) as f64,
+                _ => 0.0,
+            }
+        }),
+        "difficulty" => Ok(|ctx| ctx.header().difficulty_float()),
+        "bits" => Ok(|ctx| ctx.header().bits.to_consensus() as f64),
+        "target_log2" => Ok(|ctx| u256_to_f64(ctx.header().target().to_be_bytes()).log2()),
+        "chainwork" => Ok(|ctx| {
+            // Cumulative expected hashes to produce the chain up to and including this block
+            u256_to_f64((ctx.history.chainwork + ctx.header().work()).to_be_bytes())
+        }),
+        "retarget_epoch_position" => Ok(|ctx| (ctx.height % RETARGET_INTERVAL) as f64),
+        "hashrate_estimate" => Ok(|ctx| {
+            // Rolling hashrate estimate (hashes/second) from chain work and observed block intervals
+            ctx.history.estimated_hashrate(ctx.header(), ctx.options.hashrate_window)
+        }),
+        "fee_avg" => Ok(|ctx| {
+            let fees = calculate_block_fees(&ctx.block().txdata, ctx.height);
 
             // Calculate total vBytes for non-coinbase transactions
-            let total_vbytes: f64 = block.txdata.iter()
+            let total_vbytes: f64 = ctx.block().txdata.iter()
                 .skip(1) // Skip coinbase
                 .map(|tx| tx.weight().to_wu() as f64 / 4.0)
                 .sum();
@@ -567,19 +1974,15 @@ fn get_column_extractor(column_name: &str) -> anyhow::Result<ColumnExtractor> {
                 0.0
             }
         }),
-        "block_size" => Ok(|_block, _height, _utxo| {
-            // Note: block_size is now cached in the index, this function won't be used for block_size
-            // This is kept for compatibility, but the export function uses cached values
-            0.0
-        }),
-        "utxo_size" => Ok(|_block, _height, utxo| {
-            let utxo_set = utxo.expect("utxo_size requires UTXO data - this should have been caught by validation");
+        "block_size" => Ok(|ctx| ctx.location.block_size as f64), // cached in the index
+        "utxo_size" => Ok(|ctx| {
+            let utxo_set = ctx.utxo.expect("utxo_size requires UTXO data - this should have been caught by validation");
             utxo_set.len() as f64
         }),
-        "op_return_count" => Ok(|block, _height, _utxo| {
+        "op_return_count" => Ok(|ctx| {
             // Count total number of OP_RETURN outputs across all transactions in the block
             let mut op_return_count = 0;
-            for tx in &block.txdata {
+            for tx in &ctx.block().txdata {
                 for output in &tx.output {
                     if output.script_pubkey.is_op_return() {
                         op_return_count += 1;
@@ -588,10 +1991,10 @@ fn get_column_extractor(column_name: &str) -> anyhow::Result<ColumnExtractor> {
             }
             op_return_count as f64
         }),
-        "op_return_bytes" => Ok(|block, _height, _utxo| {
+        "op_return_bytes" => Ok(|ctx| {
             // Sum total bytes in all OP_RETURN outputs across all transactions in the block
             let mut total_op_return_bytes = 0;
-            for tx in &block.txdata {
+            for tx in &ctx.block().txdata {
                 for output in &tx.output {
                     if output.script_pubkey.is_op_return() {
                         total_op_return_bytes += output.script_pubkey.len();
@@ -600,10 +2003,10 @@ fn get_column_extractor(column_name: &str) -> anyhow::Result<ColumnExtractor> {
             }
             total_op_return_bytes as f64
         }),
-        "op_return_gt40" => Ok(|block, _height, _utxo| {
+        "op_return_gt40" => Ok(|ctx| {
             // Count OP_RETURN outputs larger than 40 bytes
             let mut count_gt40 = 0;
-            for tx in &block.txdata {
+            for tx in &ctx.block().txdata {
                 for output in &tx.output {
                     if output.script_pubkey.is_op_return() && output.script_pubkey.len() > 40 {
                         count_gt40 += 1;
@@ -612,10 +2015,10 @@ fn get_column_extractor(column_name: &str) -> anyhow::Result<ColumnExtractor> {
             }
             count_gt40 as f64
         }),
-        "op_return_gt80" => Ok(|block, _height, _utxo| {
+        "op_return_gt80" => Ok(|ctx| {
             // Count OP_RETURN outputs larger than 80 bytes
             let mut count_gt80 = 0;
-            for tx in &block.txdata {
+            for tx in &ctx.block().txdata {
                 for output in &tx.output {
                     if output.script_pubkey.is_op_return() && output.script_pubkey.len() > 80 {
                         count_gt80 += 1;
@@ -624,22 +2027,337 @@ fn get_column_extractor(column_name: &str) -> anyhow::Result<ColumnExtractor> {
             }
             count_gt80 as f64
         }),
+        "rbf_signaling_count" => Ok(|ctx| {
+            // Count non-coinbase transactions signaling BIP125 replaceability (any nSequence < 0xfffffffe)
+            ctx.block().txdata.iter()
+                .skip(1)
+                .filter(|tx| tx.is_explicitly_rbf())
+                .count() as f64
+        }),
+        "locktime_height_count" => Ok(|ctx| {
+            // Count non-coinbase transactions with an enforced, non-zero height-based nLockTime
+            ctx.block().txdata.iter()
+                .skip(1)
+                .filter(|tx| tx.is_lock_time_enabled() && tx.lock_time.is_block_height() && tx.lock_time.to_consensus_u32() != 0)
+                .count() as f64
+        }),
+        "locktime_time_count" => Ok(|ctx| {
+            // Count non-coinbase transactions with an enforced time-based nLockTime
+            ctx.block().txdata.iter()
+                .skip(1)
+                .filter(|tx| tx.is_lock_time_enabled() && tx.lock_time.is_block_time())
+                .count() as f64
+        }),
+        "relative_locktime_count" => Ok(|ctx| {
+            // Count non-coinbase transactions with at least one BIP68 relative timelock
+            // (only enforced for transaction version 2 and above)
+            ctx.block().txdata.iter()
+                .skip(1)
+                .filter(|tx| tx.version.0 >= 2 && tx.input.iter().any(|input| input.sequence.is_relative_lock_time()))
+                .count() as f64
+        }),
+        "tx_version_1" => Ok(|ctx| {
+            ctx.block().txdata.iter().skip(1).filter(|tx| tx.version.0 == 1).count() as f64
+        }),
+        "tx_version_2" => Ok(|ctx| {
+            ctx.block().txdata.iter().skip(1).filter(|tx| tx.version.0 == 2).count() as f64
+        }),
+        "tx_version_3" => Ok(|ctx| {
+            // Version 3 transactions opt in to TRUC (topologically restricted until confirmation) policy
+            ctx.block().txdata.iter().skip(1).filter(|tx| tx.version.0 == 3).count() as f64
+        }),
+        "total_output_value" => Ok(|ctx| {
+            // Sum of all non-coinbase outputs in satoshis
+            ctx.block().txdata.iter()
+                .skip(1)
+                .flat_map(|tx| tx.output.iter())
+                .map(|output| output.value.to_sat() as f64)
+                .sum()
+        }),
+        "total_input_value" => Ok(|ctx| {
+            // Sum of the values of all outputs spent in this block, in satoshis
+            let utxo_set = ctx.utxo.expect("total_input_value requires UTXO data - this should have been caught by validation");
+            let mut input_value = 0u64;
+            for tx in ctx.block().txdata.iter().skip(1) {
+                for input in &tx.input {
+                    input_value += utxo_set.get_value(&input.previous_output.txid, input.previous_output.vout)
+                        .expect("Input not found in UTXO set");
+                }
+            }
+            input_value as f64
+        }),
+        "economic_value" => Ok(|ctx| {
+            // Estimated value transferred to other parties: non-coinbase outputs
+            // excluding OP_RETURNs and each transaction's likely change output
+            let mut economic_value = 0u64;
+            for tx in ctx.block().txdata.iter().skip(1) {
+                let change_idx = likely_change_output(tx);
+                for (output_idx, output) in tx.output.iter().enumerate() {
+                    if Some(output_idx) != change_idx && !output.script_pubkey.is_op_return() {
+                        economic_value += output.value.to_sat();
+                    }
+                }
+            }
+            economic_value as f64
+        }),
+        "dust_count" => Ok(|ctx| count_dust_outputs(ctx, None)),
+        "dust_count_p2pk" => Ok(|ctx| count_dust_outputs(ctx, Some(ScriptType::P2pk))),
+        "dust_count_p2pkh" => Ok(|ctx| count_dust_outputs(ctx, Some(ScriptType::P2pkh))),
+        "dust_count_p2sh" => Ok(|ctx| count_dust_outputs(ctx, Some(ScriptType::P2sh))),
+        "dust_count_p2wpkh" => Ok(|ctx| count_dust_outputs(ctx, Some(ScriptType::P2wpkh))),
+        "dust_count_p2wsh" => Ok(|ctx| count_dust_outputs(ctx, Some(ScriptType::P2wsh))),
+        "dust_count_p2tr" => Ok(|ctx| count_dust_outputs(ctx, Some(ScriptType::P2tr))),
+        "dust_count_other" => Ok(|ctx| count_dust_outputs(ctx, Some(ScriptType::Other))),
+        "dust_value" => Ok(|ctx| {
+            // Total satoshis locked in dust outputs created by this block's transactions
+            ctx.block().txdata.iter()
+                .skip(1) // Skip coinbase
+                .flat_map(|tx| tx.output.iter())
+                .filter(|output| is_dust_output(output, ctx.options.dust_relay_fee))
+                .map(|output| output.value.to_sat() as f64)
+                .sum()
+        }),
+        "zero_value_count" => Ok(|ctx| {
+            // Count non-coinbase zero-value outputs that are not OP_RETURN
+            ctx.block().txdata.iter()
+                .skip(1) // Skip coinbase
+                .flat_map(|tx| tx.output.iter())
+                .filter(|output| output.value.to_sat() == 0 && !output.script_pubkey.is_op_return())
+                .count() as f64
+        }),
+        "utxo_total_value" => Ok(|ctx| {
+            let utxo_set = ctx.utxo.expect("utxo_total_value requires UTXO data - this should have been caught by validation");
+            utxo_set.total_value as f64
+        }),
+        "utxo_set_bytes" => Ok(|ctx| {
+            // Estimated size of the UTXO set as serialized in Bitcoin Core's chainstate
+            let utxo_set = ctx.utxo.expect("utxo_set_bytes requires UTXO data - this should have been caught by validation");
+            utxo_set.serialized_size as f64
+        }),
+        "coin_days_destroyed" => Ok(|ctx| {
+            // Sum over spent outputs of value (BTC) times days since the output was created
+            let utxo_set = ctx.utxo.expect("coin_days_destroyed requires UTXO data - this should have been caught by validation");
+            spent_output_ages(ctx, utxo_set).iter()
+                .map(|&(value, _, age_seconds)| Amount::from_sat(value).to_btc() * age_seconds as f64 / 86_400.0)
+                .sum()
+        }),
+        "utxo_dust_count" => Ok(|ctx| {
+            let utxo_set = ctx.utxo.expect("utxo_dust_count requires UTXO data - this should have been caught by validation");
+            utxo_set.dust_count as f64
+        }),
+        "sigops_cost" => Ok(|ctx| {
+            // Total sigops cost of the block, including the coinbase
+            let utxo_set = ctx.utxo.expect("sigops_cost requires UTXO data - this should have been caught by validation");
+            ctx.block().txdata.iter()
+                .map(|tx| transaction_sigops_cost(tx, ctx.height, utxo_set))
+                .sum::<u64>() as f64
+        }),
+        "sigops_utilization" => Ok(|ctx| {
+            // Percentage of the 80,000 sigops cost limit used by the block
+            let utxo_set = ctx.utxo.expect("sigops_utilization requires UTXO data - this should have been caught by validation");
+            let sigops_cost: u64 = ctx.block().txdata.iter()
+                .map(|tx| transaction_sigops_cost(tx, ctx.height, utxo_set))
+                .sum();
+            100.0 * sigops_cost as f64 / MAX_BLOCK_SIGOPS_COST as f64
+        }),
+        "reused_output_count" => Ok(|ctx| {
+            // Count outputs paying to a scriptPubKey that already appeared on chain
+            let reused_outputs = ctx.reused_outputs.expect("reused_output_count requires script reuse tracking - this should have been caught by validation");
+            reused_outputs.iter().flatten().filter(|&&reused| reused).count() as f64
+        }),
+        "reused_input_count" => Ok(|ctx| {
+            // Count inputs spending from a scriptPubKey that has been paid more than once on chain,
+            // whether the spent output was the script's first payment or a later one
+            let utxo_set = ctx.utxo.expect("reused_input_count requires UTXO data - this should have been caught by validation");
+            let reused_scripts = ctx.reused_scripts.expect("reused_input_count requires script reuse tracking - this should have been caught by validation");
+            ctx.block().txdata.iter()
+                .skip(1)
+                .flat_map(|tx| tx.input.iter())
+                .filter(|input| {
+                    let entry = utxo_set.get_entry(&input.previous_output.txid, input.previous_output.vout)
+                        .expect("Input not found in UTXO set");
+                    reused_scripts.contains(entry.script_fingerprint)
+                })
+                .count() as f64
+        }),
+        "consolidation_count" => Ok(|ctx| {
+            // Count transactions that merge many inputs into few outputs
+            let options = ctx.options;
+            ctx.block().txdata.iter()
+                .skip(1)
+                .filter(|tx| tx.input.len() >= options.consolidation_min_inputs && tx.output.len() <= options.consolidation_max_outputs)
+                .count() as f64
+        }),
+        "batch_payment_count" => Ok(|ctx| {
+            // Count transactions that pay many outputs from few inputs
+            let options = ctx.options;
+            ctx.block().txdata.iter()
+                .skip(1)
+                .filter(|tx| tx.input.len() <= options.batch_max_inputs && tx.output.len() >= options.batch_min_outputs)
+                .count() as f64
+        }),
+        "cpfp_package_count" => Ok(|ctx| {
+            // Count children that pay a higher fee rate than their in-block ancestor package,
+            // i.e. transactions that subsidise lower-fee parents in the same block
+            let utxo_set = ctx.utxo.expect("cpfp_package_count requires UTXO data - this should have been caught by validation");
+            calculate_package_stats(ctx.block(), utxo_set).iter()
+                .filter(|stats| stats.has_in_block_ancestors && stats.fee_rate > stats.ancestor_fee_rate)
+                .count() as f64
+        }),
+        "cpfp_tx_count" => Ok(|ctx| {
+            // Count transactions whose effective (package) fee rate was raised by an in-block descendant
+            let utxo_set = ctx.utxo.expect("cpfp_tx_count requires UTXO data - this should have been caught by validation");
+            calculate_package_stats(ctx.block(), utxo_set).iter()
+                .filter(|stats| stats.effective_fee_rate > stats.fee_rate)
+                .count() as f64
+        }),
         _ => Err(anyhow::anyhow!("Unknown column: {}", column_name)),
     }
 }
 
-fn column_requires_utxo(column_name: &str) -> bool {
+fn get_indexed_column_extractor(
+    column_name: &str,
+    options: &ExportOptions,
+) -> anyhow::Result<Option<(usize, IndexedColumnExtractor)>> {
+    let find_deployment = |name: &str| {
+        options.deployments.iter()
+            .position(|deployment| deployment.name == name)
+            .ok_or_else(|| anyhow::anyhow!("Unknown deployment '{}' in column {}", name, column_name))
+    };
+
+    if let Some(bucket) = column_name.strip_prefix("utxo_count_log10_").or_else(|| column_name.strip_prefix("utxo_value_log10_")) {
+        // UTXO count or value in one order-of-magnitude value bin
+        let bucket: usize = bucket.parse()
+            .ok()
+            .filter(|&bucket| bucket < VALUE_BUCKET_COUNT)
+            .ok_or_else(|| anyhow::anyhow!("Invalid value bucket in column {} (expected 0-{})", column_name, VALUE_BUCKET_COUNT - 1))?;
+        if column_name.starts_with("utxo_count_") {
+            Ok(Some((bucket, |ctx, bucket| {
+                let utxo_set = ctx.utxo.expect("utxo_count_log10_* requires UTXO data - this should have been caught by validation");
+                utxo_set.by_value_bucket[bucket].count as f64
+            })))
+        } else {
+            Ok(Some((bucket, |ctx, bucket| {
+                let utxo_set = ctx.utxo.expect("utxo_value_log10_* requires UTXO data - this should have been caught by validation");
+                utxo_set.by_value_bucket[bucket].value as f64
+            })))
+        }
+    } else if let Some(type_name) = column_name.strip_prefix("utxo_count_").or_else(|| column_name.strip_prefix("utxo_value_")) {
+        // UTXO count or value for one script type
+        let script_type = ScriptType::ALL.iter()
+            .find(|script_type| script_type.name() == type_name)
+            .ok_or_else(|| anyhow::anyhow!("Unknown script type in column {}", column_name))?;
+        if column_name.starts_with("utxo_count_") {
+            Ok(Some((*script_type as usize, |ctx, type_idx| {
+                let utxo_set = ctx.utxo.expect("utxo_count_* requires UTXO data - this should have been caught by validation");
+                utxo_set.by_script_type[type_idx].count as f64
+            })))
+        } else {
+            Ok(Some((*script_type as usize, |ctx, type_idx| {
+                let utxo_set = ctx.utxo.expect("utxo_value_* requires UTXO data - this should have been caught by validation");
+                utxo_set.by_script_type[type_idx].value as f64
+            })))
+        }
+    } else if let Some(label) = column_name.strip_prefix("hodl_wave_cum_") {
+        // Percentage of UTXO set value younger than a band's upper bound
+        let band = HODL_WAVE_BANDS.iter()
+            .position(|(band_label, _)| *band_label == label)
+            .ok_or_else(|| anyhow::anyhow!("Unknown hodl wave band in column {}", column_name))?;
+        Ok(Some((band, |ctx, band| {
+            let utxo_set = ctx.utxo.expect("hodl_wave_cum_* requires UTXO data - this should have been caught by validation");
+            if utxo_set.total_value == 0 {
+                return 0.0;
+            }
+            let value = utxo_set.value_aged_between(ctx.height, 0, HODL_WAVE_BANDS[band].1);
+            100.0 * value as f64 / utxo_set.total_value as f64
+        })))
+    } else if let Some(label) = column_name.strip_prefix("hodl_wave_") {
+        // Percentage of UTXO set value whose age falls in one band
+        let band = HODL_WAVE_BANDS.iter()
+            .position(|(band_label, _)| *band_label == label)
+            .ok_or_else(|| anyhow::anyhow!("Unknown hodl wave band in column {}", column_name))?;
+        Ok(Some((band, |ctx, band| {
+            let utxo_set = ctx.utxo.expect("hodl_wave_* requires UTXO data - this should have been caught by validation");
+            if utxo_set.total_value == 0 {
+                return 0.0;
+            }
+            let min_age = if band == 0 { 0 } else { HODL_WAVE_BANDS[band - 1].1 };
+            let max_age = HODL_WAVE_BANDS[band].1;
+            let value = utxo_set.value_aged_between(ctx.height, min_age, max_age);
+            100.0 * value as f64 / utxo_set.total_value as f64
+        })))
+    } else if let Some(bit) = column_name.strip_prefix("version_bit_") {
+        // 1 if the header version uses BIP9 semantics and sets this bit
+        let bit: usize = bit.parse()
+            .ok()
+            .filter(|&bit| bit < soft_forks::VERSION_BITS_COUNT)
+            .ok_or_else(|| anyhow::anyhow!("Invalid version bit in column {}", column_name))?;
+        Ok(Some((bit, |ctx, bit| {
+            soft_forks::signals_bit(ctx.header().version.to_consensus(), bit as u8) as u8 as f64
+        })))
+    } else if let Some(name) = column_name.strip_prefix("signal_pct_") {
+        // Percentage of blocks so far in this retarget period signaling for the deployment
+        Ok(Some((find_deployment(name)?, |ctx, index| {
+            let deployment = &ctx.options.deployments[index];
+            if !deployment.is_active_window(ctx.height) {
+                return 0.0;
+            }
+            let position = ctx.height % RETARGET_INTERVAL;
+            let previous = if position == 0 { 0 } else { ctx.history.signals.count(deployment.bit) };
+            let current = soft_forks::signals_bit(ctx.header().version.to_consensus(), deployment.bit) as u32;
+            100.0 * (previous + current) as f64 / (position + 1) as f64
+        })))
+    } else if let Some(name) = column_name.strip_prefix("signal_") {
+        // 1 if the block signals for the deployment during its signaling window
+        Ok(Some((find_deployment(name)?, |ctx, index| {
+            let deployment = &ctx.options.deployments[index];
+            let signals = soft_forks::signals_bit(ctx.header().version.to_consensus(), deployment.bit);
+            (deployment.is_active_window(ctx.height) && signals) as u8 as f64
+        })))
+    } else {
+        Ok(None)
+    }
+}
+
+// How much of a block a column needs, from cheapest to most expensive to obtain
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum DataRequirement {
+    Index,   // served from the block index without touching blk files
+    Header,  // needs only the 80-byte block header
+    Summary, // needs the header, tx count or weight, which indexes with block summaries provide
+    Block,   // needs the fully deserialized block
+    Utxo,   // needs the full block plus UTXO tracking (--utxo)
+}
+
+fn column_data_requirement(column_name: &str) -> DataRequirement {
     match column_name {
-        "fee_rates" | "utxo_size" => true,
-        _ => false,
+        "height" | "block_size" => DataRequirement::Index,
+        "timestamp"
+            | "block_interval" | "median_time_past" | "backwards_time_delta" | "hashrate_estimate"
+            | "difficulty" | "bits" | "target_log2" | "chainwork" | "retarget_epoch_position" => DataRequirement::Header,
+        "tx_count" | "block_weight" => DataRequirement::Summary,
+        name if name.starts_with("version_bit_") || name.starts_with("signal_") => DataRequirement::Header,
+        name if name.starts_with("utxo_count_") || name.starts_with("utxo_value_") || name.starts_with("hodl_wave_") => DataRequirement::Utxo,
+        "fee_rates" | "fee_rates_weighted" | "utxo_size"
+            | "package_fee_rates" | "cpfp_package_count" | "cpfp_tx_count"
+            | "total_input_value" | "utxo_dust_count"
+            | "sigops_cost" | "sigops_utilization" | "tx_sigops"
+            | "utxo_total_value" | "utxo_set_bytes"
+            | "coin_days_destroyed" | "spent_age" | "spent_age_days"
+            | "reused_input_count" => DataRequirement::Utxo,
+        _ => DataRequirement::Block,
     }
 }
 
+fn column_requires_script_reuse(column_name: &str) -> bool {
+    matches!(column_name, "reused_output_count" | "reused_input_count")
+}
+
 fn get_multi_column_extractor(base_name: &str) -> anyhow::Result<MultiColumnExtractor> {
     match base_name {
-        "tx_size" => Ok(|block, _height, _utxo| {
+        "tx_size" => Ok(|ctx| {
             // Transaction sizes in vbytes
-            let mut sizes: Vec<f64> = block.txdata.iter()
+            let mut sizes: Vec<f64> = ctx.block().txdata.iter()
                 .skip(1) // Skip coinbase
                 .map(|tx| tx.weight().to_wu() as f64 / 4.0)
                 .collect();
@@ -647,49 +2365,231 @@ fn get_multi_column_extractor(base_name: &str) -> anyhow::Result<MultiColumnExtr
             sizes.sort_by(|a, b| a.partial_cmp(b).unwrap());
             sizes
         }),
-        "fee_rates" => Ok(|block, _height, utxo| {
-            let utxo_set = utxo.expect("fee_rates requires UTXO data - this should have been caught by validation");
+        "tx_sigops" => Ok(|ctx| {
+            let utxo_set = ctx.utxo.expect("tx_sigops requires UTXO data - this should have been caught by validation");
 
-            // Calculate fee rate for each non-coinbase transaction
-            let mut fee_rates = Vec::new();
+            // Sigops cost of each non-coinbase transaction
+            let mut sigops: Vec<f64> = ctx.block().txdata.iter()
+                .skip(1) // Skip coinbase
+                .map(|tx| transaction_sigops_cost(tx, ctx.height, utxo_set) as f64)
+                .collect();
 
-            for (tx_idx, tx) in block.txdata.iter().enumerate() {
-                if tx_idx == 0 {
-                    continue; // Skip coinbase
-                }
+            sigops.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            sigops
+        }),
+        "spent_age" => Ok(|ctx| {
+            let utxo_set = ctx.utxo.expect("spent_age requires UTXO data - this should have been caught by validation");
 
-                // Calculate input value (assume all inputs are in UTXO set)
-                let mut input_value = 0u64;
-                for input in &tx.input {
-                    let value = utxo_set.get_value(&input.previous_output.txid, input.previous_output.vout)
-                        .expect("Input not found in UTXO set");
-                    input_value += value;
-                }
+            // Age in blocks of each output spent in this block
+            let mut ages: Vec<f64> = spent_output_ages(ctx, utxo_set).iter()
+                .map(|&(_, age_blocks, _)| age_blocks as f64)
+                .collect();
+
+            ages.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            ages
+        }),
+        "spent_age_days" => Ok(|ctx| {
+            let utxo_set = ctx.utxo.expect("spent_age_days requires UTXO data - this should have been caught by validation");
+
+            // Age in days of each output spent in this block
+            let mut ages: Vec<f64> = spent_output_ages(ctx, utxo_set).iter()
+                .map(|&(_, _, age_seconds)| age_seconds as f64 / 86_400.0)
+                .collect();
+
+            ages.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            ages
+        }),
+        "inputs_per_tx" => Ok(|ctx| {
+            let mut counts: Vec<f64> = ctx.block().txdata.iter()
+                .skip(1) // Skip coinbase
+                .map(|tx| tx.input.len() as f64)
+                .collect();
+
+            counts.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            counts
+        }),
+        "outputs_per_tx" => Ok(|ctx| {
+            let mut counts: Vec<f64> = ctx.block().txdata.iter()
+                .skip(1) // Skip coinbase
+                .map(|tx| tx.output.len() as f64)
+                .collect();
+
+            counts.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            counts
+        }),
+        "output_value" => Ok(|ctx| {
+            // Values of spendable non-coinbase outputs in satoshis
+            let mut values: Vec<f64> = ctx.block().txdata.iter()
+                .skip(1) // Skip coinbase
+                .flat_map(|tx| tx.output.iter())
+                .filter(|output| !output.script_pubkey.is_op_return())
+                .map(|output| output.value.to_sat() as f64)
+                .collect();
 
-                // Calculate output value
-                let output_value: u64 = tx.output.iter()
-                    .map(|output| output.value.to_sat())
-                    .sum();
+            values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            values
+        }),
+        "fee_rates" => Ok(|ctx| {
+            let utxo_set = ctx.utxo.expect("fee_rates requires UTXO data - this should have been caught by validation");
 
-                // Assert that input >= output (no value creation)
-                assert!(input_value >= output_value, "Input value {} < output value {} for transaction", input_value, output_value);
+            // Calculate fee rate for each non-coinbase transaction
+            let mut fee_rates = Vec::new();
 
-                // Calculate fee and fee rate
-                let fee = input_value - output_value;
+            for tx in ctx.block().txdata.iter().skip(1) {
                 let tx_vsize = tx.weight().to_wu() as f64 / 4.0;
 
                 if tx_vsize > 0.0 {
-                    fee_rates.push(fee as f64 / tx_vsize);
+                    fee_rates.push(transaction_fee(tx, utxo_set) as f64 / tx_vsize);
                 }
             }
 
             fee_rates.sort_by(|a, b| a.partial_cmp(b).unwrap());
             fee_rates
         }),
+        "package_fee_rates" => Ok(|ctx| {
+            let utxo_set = ctx.utxo.expect("package_fee_rates requires UTXO data - this should have been caught by validation");
+
+            // Package-aware fee rate for each non-coinbase transaction
+            let mut fee_rates: Vec<f64> = calculate_package_stats(ctx.block(), utxo_set).iter()
+                .map(|stats| stats.effective_fee_rate)
+                .collect();
+
+            fee_rates.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            fee_rates
+        }),
         _ => Err(anyhow::anyhow!("Unknown multi-column: {}", base_name)),
     }
 }
 
+fn get_weighted_multi_column_extractor(base_name: &str) -> Option<WeightedMultiColumnExtractor> {
+    match base_name {
+        "fee_rates_weighted" => Some(|ctx| {
+            let utxo_set = ctx.utxo.expect("fee_rates_weighted requires UTXO data - this should have been caught by validation");
+
+            // Fee rate of each non-coinbase transaction, weighted by its vsize so that
+            // the distribution reflects block space rather than transaction count
+            let mut weighted_fee_rates = Vec::new();
+
+            for tx in ctx.block().txdata.iter().skip(1) {
+                let tx_vsize = tx.weight().to_wu() as f64 / 4.0;
+
+                if tx_vsize > 0.0 {
+                    weighted_fee_rates.push((transaction_fee(tx, utxo_set) as f64 / tx_vsize, tx_vsize));
+                }
+            }
+
+            weighted_fee_rates.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+            weighted_fee_rates
+        }),
+        _ => None,
+    }
+}
+
+fn transaction_fee(tx: &Transaction, utxo_set: &UtxoSet) -> u64 {
+    // Calculate input value (assume all inputs are in UTXO set)
+    let mut input_value = 0u64;
+    for input in &tx.input {
+        let value = utxo_set.get_value(&input.previous_output.txid, input.previous_output.vout)
+            .expect("Input not found in UTXO set");
+        input_value += value;
+    }
+
+    // Calculate output value
+    let output_value: u64 = tx.output.iter()
+        .map(|output| output.value.to_sat())
+        .sum();
+
+    // Assert that input >= output (no value creation)
+    assert!(input_value >= output_value, "Input value {} < output value {} for transaction", input_value, output_value);
+
+    input_value - output_value
+}
+
+struct TxPackageStats {
+    fee_rate: f64,           // the transaction's own fee rate
+    ancestor_fee_rate: f64,  // fee rate of the transaction together with all its in-block ancestors
+    effective_fee_rate: f64, // best ancestor package fee rate among packages containing the transaction
+    has_in_block_ancestors: bool,
+}
+
+fn calculate_package_stats(block: &bitcoin::Block, utxo_set: &UtxoSet) -> Vec<TxPackageStats> {
+    // Index non-coinbase transactions by txid so that in-block parents can be found
+    let transactions: Vec<&Transaction> = block.txdata.iter().skip(1).collect();
+    let positions: HashMap<bitcoin::Txid, usize> = transactions.iter()
+        .enumerate()
+        .map(|(idx, tx)| (tx.txid(), idx))
+        .collect();
+
+    let fees: Vec<f64> = transactions.iter().map(|tx| transaction_fee(tx, utxo_set) as f64).collect();
+    let vsizes: Vec<f64> = transactions.iter().map(|tx| tx.weight().to_wu() as f64 / 4.0).collect();
+
+    // Parents always precede their children within a block, so ancestor sets can be
+    // built in a single forward pass
+    let mut ancestors: Vec<std::collections::BTreeSet<usize>> = Vec::with_capacity(transactions.len());
+    for tx in &transactions {
+        let mut tx_ancestors = std::collections::BTreeSet::new();
+        for input in &tx.input {
+            if let Some(&parent_idx) = positions.get(&input.previous_output.txid) {
+                if parent_idx < ancestors.len() && tx_ancestors.insert(parent_idx) {
+                    tx_ancestors.extend(ancestors[parent_idx].iter().copied());
+                }
+            }
+        }
+        ancestors.push(tx_ancestors);
+    }
+
+    let mut stats: Vec<TxPackageStats> = (0..transactions.len()).map(|idx| {
+        let package_fee = fees[idx] + ancestors[idx].iter().map(|&a| fees[a]).sum::<f64>();
+        let package_vsize = vsizes[idx] + ancestors[idx].iter().map(|&a| vsizes[a]).sum::<f64>();
+        let fee_rate = if vsizes[idx] > 0.0 { fees[idx] / vsizes[idx] } else { 0.0 };
+        let ancestor_fee_rate = if package_vsize > 0.0 { package_fee / package_vsize } else { 0.0 };
+
+        TxPackageStats {
+            fee_rate,
+            ancestor_fee_rate,
+            effective_fee_rate: ancestor_fee_rate,
+            has_in_block_ancestors: !ancestors[idx].is_empty(),
+        }
+    }).collect();
+
+    // A transaction is mined at the best rate of any ancestor package it belongs to,
+    // so descendants with a higher package rate lift their ancestors
+    for idx in 0..stats.len() {
+        let package_rate = stats[idx].ancestor_fee_rate;
+        for &ancestor_idx in &ancestors[idx] {
+            if package_rate > stats[ancestor_idx].effective_fee_rate {
+                stats[ancestor_idx].effective_fee_rate = package_rate;
+            }
+        }
+    }
+
+    stats
+}
+
+// Age of every output spent by the block's non-coinbase transactions, in blocks and seconds
+fn spent_output_ages(ctx: &ColumnContext, utxo_set: &UtxoSet) -> Vec<(u64, u32, u64)> {
+    // (value_sats, age_blocks, age_seconds)
+    let block_time = ctx.header().time;
+    let mut ages = Vec::new();
+    for tx in ctx.block().txdata.iter().skip(1) {
+        for input in &tx.input {
+            let entry = utxo_set.get_entry(&input.previous_output.txid, input.previous_output.vout)
+                .expect("Input not found in UTXO set");
+            let created_time = if entry.height == ctx.height {
+                block_time
+            } else {
+                ctx.history.block_time(entry.height).unwrap_or(block_time)
+            };
+            ages.push((
+                entry.value,
+                ctx.height - entry.height,
+                block_time.saturating_sub(created_time) as u64,
+            ));
+        }
+    }
+    ages
+}
+
 fn calculate_quantiles(sorted_data: &[f64], quantiles: &[f64]) -> Vec<f64> {
     if sorted_data.is_empty() {
         return vec![0.0; quantiles.len()];
@@ -715,12 +2615,64 @@ fn calculate_quantiles(sorted_data: &[f64], quantiles: &[f64]) -> Vec<f64> {
     }).collect()
 }
 
+fn calculate_weighted_quantiles(sorted_data: &[(f64, f64)], quantiles: &[f64]) -> Vec<f64> {
+    // sorted_data holds (value, weight) pairs sorted by value
+    let total_weight: f64 = sorted_data.iter().map(|(_, weight)| weight).sum();
+    if sorted_data.is_empty() || total_weight <= 0.0 {
+        return vec![0.0; quantiles.len()];
+    }
+
+    quantiles.iter().map(|&q| {
+        if q == 0.0 {
+            sorted_data[0].0
+        } else if q == 100.0 {
+            sorted_data[sorted_data.len() - 1].0
+        } else {
+            // First value at which the cumulative weight reaches q% of the total
+            let target = (q / 100.0) * total_weight;
+            let mut cumulative = 0.0;
+            for &(value, weight) in sorted_data {
+                cumulative += weight;
+                if cumulative >= target {
+                    return value;
+                }
+            }
+            sorted_data[sorted_data.len() - 1].0
+        }
+    }).collect()
+}
+
+// The network to export for: the index's own unless --network overrides an index that
+// does not record one. A --network that contradicts the index is an error.
+fn export_network(
+    requested: Option<bitcoin::Network>,
+    indexed: Option<bitcoin::Network>,
+    index_path: &Path,
+) -> anyhow::Result<bitcoin::Network> {
+    match (requested, indexed) {
+        (Some(requested), Some(indexed)) if requested != indexed => Err(anyhow::anyhow!(
+            "--network {} does not match index {}, which was built for {}",
+            requested,
+            index_path.display(),
+            indexed
+        )),
+        (Some(network), _) | (None, Some(network)) => Ok(network),
+        (None, None) => {
+            println!("Warning: index {} does not record its network; assuming bitcoin (pass --network to choose)",
+                     index_path.display());
+            Ok(bitcoin::Network::Bitcoin)
+        }
+    }
+}
+
 fn export_arrow_file(
     datadir: PathBuf,
+    index_path: PathBuf,
     filename: PathBuf,
     columns: Vec<String>,
     max_height: Option<u32>,
     utxo: bool,
+    mut options: ExportOptions,
 ) -> anyhow::Result<()> {
     use arrow::array::{Float64Builder, RecordBatch, RecordBatchWriter};
     use arrow::datatypes::{DataType, Field, Schema};
@@ -729,8 +2681,15 @@ fn export_arrow_file(
     use std::sync::Arc;
 
     // Load the index
-    let block_index = BlockIndex::load_from_file(INDEX_PATH)?;
     let xor_key = load_xor_key(&datadir)?;
+    let block_index = open_index(&index_path, &datadir, &xor_key)?;
+
+    // Deployment heights depend on the network, which the index records
+    let network = export_network(options.network, block_index.metadata.network, &index_path)?;
+    options.deployments = match &options.deployments_file {
+        Some(path) => soft_forks::load_deployments(path, network)?,
+        None => soft_forks::default_deployments(network),
+    };
 
     // Determine height range
     let tip_height = block_index.tip_height;
@@ -742,12 +2701,12 @@ fn export_arrow_file(
     let mut expanded_column_names = Vec::new();
 
     for column_input in &columns {
-        let spec = parse_column_spec(column_input)?;
+        let spec = parse_column_spec(column_input, &options)?;
         match &spec {
-            ColumnSpec::Single(name, _) => {
+            ColumnSpec::Single(name, _) | ColumnSpec::Indexed(name, _, _) => {
                 expanded_column_names.push(name.clone());
             }
-            ColumnSpec::Multi(base_name, quantiles, _) => {
+            ColumnSpec::Multi(base_name, quantiles, _) | ColumnSpec::WeightedMulti(base_name, quantiles, _) => {
                 for &q in quantiles {
                     expanded_column_names.push(format!("{}_{}", base_name, q as u32));
                 }
@@ -760,7 +2719,7 @@ fn export_arrow_file(
     if !utxo {
         for column_input in &columns {
             let base_name = column_input.split('[').next().unwrap_or(column_input);
-            if column_requires_utxo(base_name) {
+            if column_data_requirement(base_name) == DataRequirement::Utxo {
                 return Err(anyhow::anyhow!(
                     "Column '{}' requires UTXO tracking for accurate calculations.\n\
                      Please add the --utxo flag to enable UTXO tracking:\n\
@@ -775,8 +2734,52 @@ fn export_arrow_file(
         }
     }
 
+    // Validate that script reuse columns have the --script-reuse flag
+    let script_reuse = options.script_reuse;
+    if !script_reuse {
+        for column_input in &columns {
+            if column_requires_script_reuse(column_input) {
+                return Err(anyhow::anyhow!(
+                    "Column '{}' requires script reuse tracking.\n\
+                     Please add the --script-reuse flag (and --script-filter-mb to size its filter):\n\
+                     \n\
+                     cargo run --bin main -- export {} --script-reuse [other options]",
+                    column_input,
+                    filename.display()
+                ));
+            }
+        }
+    }
+
+    // Read no more of each block than the most demanding column needs
+    // (UTXO and script reuse tracking always need every block's transactions)
+    let read_level = columns.iter()
+        .map(|column_input| {
+            let base_name = column_input.split('[').next().unwrap_or(column_input);
+            column_data_requirement(base_name)
+        })
+        .chain((utxo || script_reuse).then_some(DataRequirement::Block))
+        .max()
+        .unwrap_or(DataRequirement::Index);
+
+    // Header and summary columns come straight from the index when it carries block summaries
+    let read_level = match read_level {
+        DataRequirement::Header | DataRequirement::Summary if block_index.has_summaries => DataRequirement::Index,
+        DataRequirement::Summary => DataRequirement::Block,
+        level => level,
+    };
+    if read_level == DataRequirement::Header {
+        println!("Note: index {} has no block summaries; rebuild it with build-index to skip reading headers from blk files",
+                 index_path.display());
+    }
+
     println!("Exporting {} columns (expanded to {} columns) from height {} to {}",
              columns.len(), expanded_column_names.len(), export_min_height, export_max_height);
+    match read_level {
+        DataRequirement::Index => println!("All columns are served by the index, no block files will be read"),
+        DataRequirement::Header => println!("All columns are header-derived, reading block headers only"),
+        DataRequirement::Summary | DataRequirement::Block | DataRequirement::Utxo => {}
+    }
 
     // Create Arrow schema using expanded column names
     let fields: Vec<Field> = expanded_column_names.iter()
@@ -791,87 +2794,172 @@ fn export_arrow_file(
 
     // Initialize UTXO set if needed
     let mut utxo_set = if utxo {
-        Some(UtxoSet::new())
+        Some(UtxoSet::new(options.dust_relay_fee, export_max_height))
+    } else {
+        None
+    };
+
+    // Initialize the seen-scripts filter if needed
+    let mut seen_scripts = if script_reuse {
+        Some(SeenScripts::new(options.script_filter_mb))
     } else {
         None
     };
 
+    // Scripts seen at least twice, so that spends from reused scripts can be counted.
+    // Far fewer scripts are reused than seen, so a quarter of the memory keeps a similar rate
+    let mut reused_scripts = if script_reuse {
+        Some(SeenScripts::new(options.script_filter_mb / 4))
+    } else {
+        None
+    };
+
+    // Headers of recently exported blocks, for columns that look back along the chain
+    let mut header_history = HeaderHistory::new(options.hashrate_window);
+
     // Process blocks and collect data
+    let mut reader = MappedBlockReader::new(xor_key);
     let mut processed_count = 0;
     for height in export_min_height..=export_max_height {
-        if let Some(location) = block_index.blocks.get(&height) {
-            let mut reader = BlockFileReader::new_with_xor_key(&location.file_path, xor_key)?;
-            reader.seek_to_offset(location.file_offset)?;
-
-            if let Some((block, _offset)) = reader.read_next_block()? {
-                // UTXO tracking: Add block outputs to UTXO set
-                if let Some(ref mut utxo) = utxo_set {
-                    for (_tx_idx, tx) in block.txdata.iter().enumerate() {
-                        let txid = tx.txid();
-                        for (output_idx, output) in tx.output.iter().enumerate() {
-                            // Skip OP_RETURN outputs (provably unspendable)
-                            if output.script_pubkey.is_op_return() {
-                                continue;
-                            }
-                            utxo.add_output(&txid, output_idx as u32, output.value.to_sat(), height)?;
+        if let Some(location) = block_index.get_block_location(height) {
+            let (header, block) = if read_level == DataRequirement::Index {
+                (location.summary.map(|summary| summary.header), None)
+            } else {
+                // Header-only exports never deserialize the block's transactions
+                if read_level == DataRequirement::Header {
+                    (Some(reader.read_header_at(height, &location)?), None)
+                } else {
+                    let block = reader.read_block_at(height, &location, options.check_merkle)?;
+                    (Some(block.header), Some(block))
+                }
+            };
+
+            // Script reuse tracking: flag outputs paying to a script seen earlier on chain
+            // and record that script as reused
+            let mut script_fingerprints: Vec<Vec<u64>> = Vec::new();
+            let mut reused_outputs: Vec<Vec<bool>> = Vec::new();
+            if let (Some(seen), Some(reused), Some(block)) = (seen_scripts.as_mut(), reused_scripts.as_mut(), block.as_ref()) {
+                script_fingerprints = block.txdata.iter()
+                    .map(|tx| {
+                        tx.output.iter()
+                            .map(|output| script_fingerprint(output.script_pubkey.as_bytes()))
+                            .collect()
+                    })
+                    .collect();
+                reused_outputs = block.txdata.iter()
+                    .zip(&script_fingerprints)
+                    .map(|(tx, fingerprints)| {
+                        tx.output.iter()
+                            .zip(fingerprints)
+                            .map(|(output, &fingerprint)| {
+                                let script_reused = !output.script_pubkey.is_op_return()
+                                    && seen.check_and_insert(fingerprint);
+                                if script_reused {
+                                    reused.check_and_insert(fingerprint);
+                                }
+                                script_reused
+                            })
+                            .collect()
+                    })
+                    .collect();
+            }
+
+            // UTXO tracking: Add block outputs to UTXO set
+            if let (Some(utxo), Some(block)) = (utxo_set.as_mut(), block.as_ref()) {
+                for (tx_idx, tx) in block.txdata.iter().enumerate() {
+                    let txid = tx.txid();
+                    for (output_idx, output) in tx.output.iter().enumerate() {
+                        // Skip OP_RETURN outputs (provably unspendable)
+                        if output.script_pubkey.is_op_return() {
+                            continue;
                         }
+                        let fingerprint = script_fingerprints.get(tx_idx)
+                            .and_then(|fingerprints| fingerprints.get(output_idx))
+                            .copied()
+                            .unwrap_or(0);
+                        utxo.add_output(&txid, output_idx as u32, output, height, fingerprint)?;
                     }
                 }
+            }
 
-                // Extract values for each column spec
-                let mut builder_idx = 0;
-
-                for spec in &column_specs {
-                    match spec {
-                        ColumnSpec::Single(name, extractor) => {
-                            let value = if name == "block_size" {
-                                // Use cached block size from index
-                                location.block_size as f64
-                            } else {
-                                // Use extractor function
-                                extractor(&block, height, utxo_set.as_ref())
-                            };
-                            builders[builder_idx].append_value(value);
-                            builder_idx += 1;
-                        }
-                        ColumnSpec::Multi(_, quantiles, extractor) => {
-                            // Extract all values and calculate quantiles
-                            let data = extractor(&block, height, utxo_set.as_ref());
-                            let quantile_values = calculate_quantiles(&data, quantiles);
-
-                            // Append each quantile value to its respective builder
-                            for value in quantile_values {
-                                builders[builder_idx].append_value(value);
-                                builder_idx += 1;
-                            }
-                        }
+            // UTXO tracking: Mark block inputs for removal. Spent entries stay
+            // readable for per-input columns until the removals are committed
+            // below, while set-level columns already exclude them
+            if let (Some(utxo), Some(block)) = (utxo_set.as_mut(), block.as_ref()) {
+                for (tx_idx, tx) in block.txdata.iter().enumerate() {
+                    if tx_idx == 0 {
+                        continue; // Skip coinbase (no inputs to spend)
+                    }
+                    for input in &tx.input {
+                        utxo.mark_for_removal(&input.previous_output.txid, input.previous_output.vout);
                     }
                 }
+            }
 
-                // UTXO tracking: Mark block inputs for removal and commit
-                if let Some(ref mut utxo) = utxo_set {
-                    for (tx_idx, tx) in block.txdata.iter().enumerate() {
-                        if tx_idx == 0 {
-                            continue; // Skip coinbase (no inputs to spend)
-                        }
-                        for input in &tx.input {
-                            utxo.mark_for_removal(&input.previous_output.txid, input.previous_output.vout);
+            // Extract values for each column spec
+            let ctx = ColumnContext {
+                location: &location,
+                header: header.as_ref(),
+                block: block.as_ref(),
+                height,
+                utxo: utxo_set.as_ref(),
+                reused_outputs: seen_scripts.as_ref().map(|_| reused_outputs.as_slice()),
+                reused_scripts: reused_scripts.as_ref(),
+                history: &header_history,
+                options: &options,
+            };
+            let mut builder_idx = 0;
+
+            for spec in &column_specs {
+                match spec {
+                    ColumnSpec::Single(_, extractor) => {
+                        builders[builder_idx].append_value(extractor(&ctx));
+                        builder_idx += 1;
+                    }
+                    ColumnSpec::Indexed(_, index, extractor) => {
+                        builders[builder_idx].append_value(extractor(&ctx, *index));
+                        builder_idx += 1;
+                    }
+                    ColumnSpec::Multi(_, quantiles, extractor) => {
+                        // Extract all values and calculate quantiles
+                        let data = extractor(&ctx);
+                        let quantile_values = calculate_quantiles(&data, quantiles);
+
+                        // Append each quantile value to its respective builder
+                        for value in quantile_values {
+                            builders[builder_idx].append_value(value);
+                            builder_idx += 1;
                         }
                     }
-                    utxo.commit_removals();
+                    ColumnSpec::WeightedMulti(_, quantiles, extractor) => {
+                        let data = extractor(&ctx);
+                        let quantile_values = calculate_weighted_quantiles(&data, quantiles);
 
-                    // Log UTXO set size periodically
-                    if processed_count % 1000 == 0 && processed_count > 0 {
-                        println!("Block {}: UTXO set size: {} UTXOs", processed_count, utxo.len());
+                        for value in quantile_values {
+                            builders[builder_idx].append_value(value);
+                            builder_idx += 1;
+                        }
                     }
                 }
+            }
+
+            if let Some(header) = header {
+                header_history.push(height, header);
+            }
+
+            // UTXO tracking: Drop the entries spent by this block
+            if let Some(utxo) = utxo_set.as_mut() {
+                utxo.commit_removals();
 
-                processed_count += 1;
-                if processed_count % 10000 == 0 {
-                    println!("Processed {} blocks...", processed_count);
+                // Log UTXO set size periodically
+                if processed_count % 1000 == 0 && processed_count > 0 {
+                    println!("Block {}: UTXO set size: {} UTXOs", processed_count, utxo.len());
                 }
-            } else {
-                return Err(anyhow::anyhow!("Could not read block at height {}", height));
+            }
+
+            processed_count += 1;
+            if processed_count % 10000 == 0 {
+                println!("Processed {} blocks...", processed_count);
             }
         } else {
             return Err(anyhow::anyhow!("Block at height {} not found in index", height));
@@ -893,6 +2981,14 @@ fn export_arrow_file(
     writer.close()?;
 
     println!("Successfully exported {} rows to {}", processed_count, filename.display());
+    if let Some(seen) = &seen_scripts {
+        println!("Seen-scripts filter holds {} scripts, estimated false positive rate {:.4}%",
+                 seen.len(), seen.false_positive_rate() * 100.0);
+    }
+    if let Some(reused) = &reused_scripts {
+        println!("Reused-scripts filter holds {} scripts, estimated false positive rate {:.4}%",
+                 reused.len(), reused.false_positive_rate() * 100.0);
+    }
 
     // Immediately verify the exported file by reopening it
     println!("\n🔍 Verifying exported Arrow file...");
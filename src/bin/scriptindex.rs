@@ -0,0 +1,342 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use anyhow::{Result, anyhow};
+use bitcoin::{BlockHash, OutPoint, Script, Txid};
+use bitcoin::hashes::{Hash, sha256};
+use memmap2::Mmap;
+use crate::bucket_sort::{self, BucketSorter, FANOUT_SIZE};
+
+// On-disk layout:
+//
+//   header:  magic "SCIX" | version u32 | tip_height u32 | tip_hash [u8; 32] | entry_count u64
+//   fanout:  257 x u64, entries fanout[b]..fanout[b + 1] have script hashes starting with byte b
+//   entries: entry_count x ENTRY_SIZE, sorted bytewise
+//
+// Each entry is script hash prefix [u8; 16] | height u32 | position u32 | direction u8
+// | index u32 | txid [u8; 32] | value u64. Height, position and index are big-endian so the
+// bytewise sort lists each script's history in chain order; everything else is little-endian.
+// For received entries txid:index is the output itself; for spent entries it is the
+// spending transaction and input, and value is that of the output being spent.
+const SCRIPTINDEX_MAGIC: [u8; 4] = *b"SCIX";
+const SCRIPTINDEX_VERSION: u32 = 1;
+const HEADER_SIZE: usize = 52;
+const ENTRY_SIZE: usize = 69;
+// Histories are keyed by this many bytes of the script hash and entries do not store the
+// script itself, so two scripts sharing a prefix would have their histories merged. Reaching
+// that by chance is negligible at 128 bits; address history warns if the balance goes negative.
+pub const SCRIPT_HASH_SIZE: usize = 16;
+
+// Within a transaction, inputs are spent before outputs are created
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Spent = 0,
+    Received = 1,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ScriptHistoryEntry {
+    pub height: u32,
+    pub position: u32, // transaction's index within its block
+    pub direction: Direction,
+    pub index: u32,    // output index when received, input index when spent
+    pub txid: Txid,
+    pub value: u64,
+}
+
+// SHA256 of the scriptPubKey (as used by Electrum servers), truncated for the index
+pub fn script_hash(script: &Script) -> [u8; SCRIPT_HASH_SIZE] {
+    sha256::Hash::hash(script.as_bytes()).as_byte_array()[0..SCRIPT_HASH_SIZE].try_into().unwrap()
+}
+
+pub struct ScriptIndexBuilder {
+    sorter: BucketSorter<ENTRY_SIZE>,
+}
+
+impl ScriptIndexBuilder {
+    pub fn new(path: &Path) -> Result<Self> {
+        Ok(ScriptIndexBuilder {
+            sorter: BucketSorter::new(path.with_extension("idx.buckets"))?,
+        })
+    }
+
+    pub fn add(&mut self, script_hash: &[u8; SCRIPT_HASH_SIZE], entry: &ScriptHistoryEntry) -> Result<()> {
+        let mut bytes = [0u8; ENTRY_SIZE];
+        bytes[0..16].copy_from_slice(script_hash);
+        bytes[16..20].copy_from_slice(&entry.height.to_be_bytes());
+        bytes[20..24].copy_from_slice(&entry.position.to_be_bytes());
+        bytes[24] = entry.direction as u8;
+        bytes[25..29].copy_from_slice(&entry.index.to_be_bytes());
+        bytes[29..61].copy_from_slice(entry.txid.as_byte_array());
+        bytes[61..69].copy_from_slice(&entry.value.to_le_bytes());
+        self.sorter.add(&bytes)
+    }
+
+    pub fn len(&self) -> u64 {
+        self.sorter.len()
+    }
+
+    pub fn finish(self, path: &Path, tip_height: u32, tip_hash: BlockHash) -> Result<()> {
+        let temp_path = path.with_extension("idx.tmp");
+        let mut writer = BufWriter::new(File::create(&temp_path)?);
+
+        writer.write_all(&SCRIPTINDEX_MAGIC)?;
+        writer.write_all(&SCRIPTINDEX_VERSION.to_le_bytes())?;
+        writer.write_all(&tip_height.to_le_bytes())?;
+        writer.write_all(tip_hash.as_byte_array())?;
+        writer.write_all(&self.sorter.len().to_le_bytes())?;
+        self.sorter.finish(&mut writer)?;
+
+        writer.into_inner()?.sync_all()?;
+        fs::rename(&temp_path, path)?;
+        Ok(())
+    }
+}
+
+// Records spilled by SpendResolver: outpoint txid [u8; 32] | vout u32 BE | kind u8, then
+// for an output its script hash [u8; 16] | value u64, and for a spend its height u32
+// | position u32 | input index u32 | spending txid [u8; 32]. Sorting puts each output
+// directly ahead of the spend of it.
+const SPEND_RECORD_SIZE: usize = 81;
+const RECORD_OUTPUT: u8 = 0;
+const RECORD_SPEND: u8 = 1;
+
+// Attributes each spend to the script and value of the output it spends without holding
+// the UTXO set in memory: outputs and spends are spilled to disk, sorted by outpoint and
+// matched in a single pass. Like ScriptIndexBuilder, it holds one bucket (1/256 of the
+// records) in memory while sorting.
+pub struct SpendResolver {
+    sorter: BucketSorter<SPEND_RECORD_SIZE>,
+}
+
+impl SpendResolver {
+    pub fn new(path: &Path) -> Result<Self> {
+        Ok(SpendResolver {
+            sorter: BucketSorter::new(path.with_extension("idx.spends"))?,
+        })
+    }
+
+    fn record(outpoint: &OutPoint, kind: u8) -> [u8; SPEND_RECORD_SIZE] {
+        let mut bytes = [0u8; SPEND_RECORD_SIZE];
+        bytes[0..32].copy_from_slice(outpoint.txid.as_byte_array());
+        bytes[32..36].copy_from_slice(&outpoint.vout.to_be_bytes());
+        bytes[36] = kind;
+        bytes
+    }
+
+    pub fn add_output(&mut self, outpoint: &OutPoint, script_hash: &[u8; SCRIPT_HASH_SIZE], value: u64) -> Result<()> {
+        let mut bytes = Self::record(outpoint, RECORD_OUTPUT);
+        bytes[37..53].copy_from_slice(script_hash);
+        bytes[53..61].copy_from_slice(&value.to_le_bytes());
+        self.sorter.add(&bytes)
+    }
+
+    pub fn add_spend(&mut self, outpoint: &OutPoint, height: u32, position: u32, index: u32, txid: &Txid) -> Result<()> {
+        let mut bytes = Self::record(outpoint, RECORD_SPEND);
+        bytes[37..41].copy_from_slice(&height.to_le_bytes());
+        bytes[41..45].copy_from_slice(&position.to_le_bytes());
+        bytes[45..49].copy_from_slice(&index.to_le_bytes());
+        bytes[49..81].copy_from_slice(txid.as_byte_array());
+        self.sorter.add(&bytes)
+    }
+
+    // Add a spent entry to the builder for every spend, returning how many were added
+    pub fn resolve(self, builder: &mut ScriptIndexBuilder) -> Result<u64> {
+        // The most recent output record: its outpoint, script hash and value
+        let mut output: Option<([u8; 36], [u8; SCRIPT_HASH_SIZE], u64)> = None;
+        let mut spends = 0u64;
+
+        self.sorter.for_each_sorted(|bytes| {
+            let outpoint: [u8; 36] = bytes[0..36].try_into().unwrap();
+            if bytes[36] == RECORD_OUTPUT {
+                let script_hash = bytes[37..53].try_into().unwrap();
+                let value = u64::from_le_bytes(bytes[53..61].try_into().unwrap());
+                output = Some((outpoint, script_hash, value));
+                return Ok(());
+            }
+
+            let height = u32::from_le_bytes(bytes[37..41].try_into().unwrap());
+            let txid = Txid::from_byte_array(bytes[49..81].try_into().unwrap());
+            let (script_hash, value) = match &output {
+                Some((output_outpoint, script_hash, value)) if *output_outpoint == outpoint => (*script_hash, *value),
+                _ => {
+                    let spent = OutPoint {
+                        txid: Txid::from_byte_array(outpoint[0..32].try_into().unwrap()),
+                        vout: u32::from_be_bytes(outpoint[32..36].try_into().unwrap()),
+                    };
+                    return Err(anyhow!("Transaction {} at height {} spends unknown output {}", txid, height, spent));
+                }
+            };
+
+            spends += 1;
+            builder.add(&script_hash, &ScriptHistoryEntry {
+                height,
+                position: u32::from_le_bytes(bytes[41..45].try_into().unwrap()),
+                direction: Direction::Spent,
+                index: u32::from_le_bytes(bytes[45..49].try_into().unwrap()),
+                txid,
+                value,
+            })
+        })?;
+
+        Ok(spends)
+    }
+}
+
+// Memory-mapped, read-only view of a saved script index
+pub struct ScriptIndex {
+    mmap: Mmap,
+    pub tip_height: u32,
+    pub tip_hash: BlockHash,
+}
+
+impl ScriptIndex {
+    pub fn load_from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path)?;
+        // SAFETY: the script index is only ever replaced by rename, never modified in place
+        let mmap = unsafe { Mmap::map(&file)? };
+
+        if mmap.len() < HEADER_SIZE + FANOUT_SIZE || mmap[0..4] != SCRIPTINDEX_MAGIC {
+            return Err(anyhow!("{} is not a script index. Please rebuild it with build-scriptindex.", path.display()));
+        }
+
+        let version = u32::from_le_bytes(mmap[4..8].try_into().unwrap());
+        if version != SCRIPTINDEX_VERSION {
+            return Err(anyhow!("Script index {} has format version {}, but this build uses version {}. \
+                                Please rebuild it with build-scriptindex.",
+                               path.display(), version, SCRIPTINDEX_VERSION));
+        }
+
+        let tip_height = u32::from_le_bytes(mmap[8..12].try_into().unwrap());
+        let tip_hash = BlockHash::from_byte_array(mmap[12..44].try_into().unwrap());
+        let entry_count = u64::from_le_bytes(mmap[44..52].try_into().unwrap());
+
+        let expected_len = HEADER_SIZE + FANOUT_SIZE + entry_count as usize * ENTRY_SIZE;
+        if mmap.len() != expected_len {
+            return Err(anyhow!("Script index {} has {} bytes, expected {} for {} entries",
+                               path.display(), mmap.len(), expected_len, entry_count));
+        }
+
+        Ok(ScriptIndex {
+            mmap,
+            tip_height,
+            tip_hash,
+        })
+    }
+
+    // Every output paying to the script and every spend of one, in chain order
+    pub fn history(&self, script: &Script) -> Vec<ScriptHistoryEntry> {
+        bucket_sort::find_prefix::<ENTRY_SIZE>(&self.mmap[HEADER_SIZE..], &script_hash(script))
+            .chunks_exact(ENTRY_SIZE)
+            .map(|bytes| ScriptHistoryEntry {
+                height: u32::from_be_bytes(bytes[16..20].try_into().unwrap()),
+                position: u32::from_be_bytes(bytes[20..24].try_into().unwrap()),
+                direction: if bytes[24] == Direction::Spent as u8 { Direction::Spent } else { Direction::Received },
+                index: u32::from_be_bytes(bytes[25..29].try_into().unwrap()),
+                txid: Txid::from_byte_array(bytes[29..61].try_into().unwrap()),
+                value: u64::from_le_bytes(bytes[61..69].try_into().unwrap()),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::ScriptBuf;
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("scriptindex_test_{}_{}.idx", std::process::id(), name))
+    }
+
+    fn txid(byte: u8) -> Txid {
+        Txid::from_byte_array([byte; 32])
+    }
+
+    fn received(builder: &mut ScriptIndexBuilder, resolver: &mut SpendResolver, script: &Script,
+                height: u32, position: u32, outpoint: OutPoint, value: u64) {
+        let hash = script_hash(script);
+        builder.add(&hash, &ScriptHistoryEntry {
+            height,
+            position,
+            direction: Direction::Received,
+            index: outpoint.vout,
+            txid: outpoint.txid,
+            value,
+        }).unwrap();
+        resolver.add_output(&outpoint, &hash, value).unwrap();
+    }
+
+    // (height, position, direction, index, txid, value) for easy comparison
+    fn summarize(history: &[ScriptHistoryEntry]) -> Vec<(u32, u32, Direction, u32, Txid, u64)> {
+        history.iter()
+            .map(|entry| (entry.height, entry.position, entry.direction, entry.index, entry.txid, entry.value))
+            .collect()
+    }
+
+    #[test]
+    fn resolves_spends_and_lists_history_in_chain_order() {
+        let path = temp_path("resolve");
+        let script_a = ScriptBuf::from_bytes(vec![0x51]);
+        let script_b = ScriptBuf::from_bytes(vec![0x52]);
+        let (coinbase, payment, duplicate, sweep) = (txid(1), txid(2), txid(3), txid(4));
+
+        let mut builder = ScriptIndexBuilder::new(&path).unwrap();
+        let mut resolver = SpendResolver::new(&path).unwrap();
+
+        // The spend is recorded before the output it spends; sorting must still match them
+        resolver.add_spend(&OutPoint::new(coinbase, 0), 2, 1, 0, &payment).unwrap();
+        received(&mut builder, &mut resolver, &script_a, 1, 0, OutPoint::new(coinbase, 0), 50);
+
+        // A transaction that spends from and pays back to the same script
+        received(&mut builder, &mut resolver, &script_a, 2, 1, OutPoint::new(payment, 0), 30);
+        received(&mut builder, &mut resolver, &script_b, 2, 1, OutPoint::new(payment, 1), 20);
+
+        // A coinbase repeated in a later block (as before BIP30) creates the same outpoint twice
+        received(&mut builder, &mut resolver, &script_b, 3, 0, OutPoint::new(duplicate, 0), 10);
+        received(&mut builder, &mut resolver, &script_b, 4, 0, OutPoint::new(duplicate, 0), 10);
+        resolver.add_spend(&OutPoint::new(duplicate, 0), 5, 1, 0, &sweep).unwrap();
+
+        assert_eq!(resolver.resolve(&mut builder).unwrap(), 2);
+        let tip_hash = BlockHash::from_byte_array([5; 32]);
+        builder.finish(&path, 5, tip_hash).unwrap();
+
+        let index = ScriptIndex::load_from_file(&path).unwrap();
+        assert_eq!((index.tip_height, index.tip_hash), (5, tip_hash));
+
+        // Within a transaction the spend comes before the new output
+        assert_eq!(summarize(&index.history(&script_a)), vec![
+            (1, 0, Direction::Received, 0, coinbase, 50),
+            (2, 1, Direction::Spent, 0, payment, 50),
+            (2, 1, Direction::Received, 0, payment, 30),
+        ]);
+        assert_eq!(summarize(&index.history(&script_b)), vec![
+            (2, 1, Direction::Received, 1, payment, 20),
+            (3, 0, Direction::Received, 0, duplicate, 10),
+            (4, 0, Direction::Received, 0, duplicate, 10),
+            (5, 1, Direction::Spent, 0, sweep, 10),
+        ]);
+        assert!(index.history(&ScriptBuf::from_bytes(vec![0x53])).is_empty());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_a_spend_of_an_unknown_output() {
+        let path = temp_path("unknown");
+        let mut builder = ScriptIndexBuilder::new(&path).unwrap();
+        let mut resolver = SpendResolver::new(&path).unwrap();
+
+        let script = ScriptBuf::from_bytes(vec![0x51]);
+        received(&mut builder, &mut resolver, &script, 1, 0, OutPoint::new(txid(1), 0), 50);
+        resolver.add_spend(&OutPoint::new(txid(1), 1), 2, 1, 0, &txid(2)).unwrap();
+
+        assert!(resolver.resolve(&mut builder).is_err());
+
+        drop(builder);
+        fs::remove_dir_all(path.with_extension("idx.spends")).unwrap();
+        fs::remove_dir_all(path.with_extension("idx.buckets")).unwrap();
+    }
+}
@@ -0,0 +1,99 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+// Bit positions probed per script
+const NUM_HASHES: u32 = 5;
+
+// Compact, probabilistic record of a set of scriptPubKeys (a Bloom filter), keyed by
+// script fingerprint so that a script can be looked up again later from its UTXO entry.
+//
+// Lookups never miss a script that was inserted, but may report an unseen script as
+// seen. With m bits, k = NUM_HASHES probes and n distinct scripts inserted, the
+// false positive rate is approximately (1 - e^(-k*n/m))^k: about 0.6% for 1.5 billion
+// scripts in the default 2048 MB, rising to 6.6% in 1024 MB. Fingerprint collisions
+// add roughly n / 2^64 on top, which is negligible.
+pub struct SeenScripts {
+    bits: Vec<u64>,
+    num_bits: u64,
+    inserted: u64,
+}
+
+// 64-bit fingerprint of a scriptPubKey, the key used by SeenScripts
+pub fn script_fingerprint(script: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    script.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl SeenScripts {
+    pub fn new(size_mb: usize) -> Self {
+        let words = (size_mb.max(1) * 1024 * 1024 / 8).max(1);
+        SeenScripts {
+            bits: vec![0; words],
+            num_bits: words as u64 * 64,
+            inserted: 0,
+        }
+    }
+
+    // Bit positions probed for a fingerprint, by double hashing
+    fn probes(fingerprint: u64, num_bits: u64) -> impl Iterator<Item = (usize, u64)> {
+        let mut hasher = DefaultHasher::new();
+        (fingerprint, 0x9e37_79b9_7f4a_7c15u64).hash(&mut hasher);
+        let step = hasher.finish() | 1; // odd, so successive probes never repeat a position early
+
+        (0..NUM_HASHES as u64).map(move |i| {
+            let bit = fingerprint.wrapping_add(i.wrapping_mul(step)) % num_bits;
+            ((bit / 64) as usize, 1u64 << (bit % 64))
+        })
+    }
+
+    // Record the script, returning whether it had (probably) been seen before
+    pub fn check_and_insert(&mut self, fingerprint: u64) -> bool {
+        let mut seen = true;
+
+        for (word, mask) in Self::probes(fingerprint, self.num_bits) {
+            if self.bits[word] & mask == 0 {
+                seen = false;
+                self.bits[word] |= mask;
+            }
+        }
+
+        if !seen {
+            self.inserted += 1;
+        }
+        seen
+    }
+
+    // Whether the script has (probably) been recorded
+    pub fn contains(&self, fingerprint: u64) -> bool {
+        Self::probes(fingerprint, self.num_bits).all(|(word, mask)| self.bits[word] & mask != 0)
+    }
+
+    // Estimated probability that an unseen script is reported as seen, given the fill so far
+    pub fn false_positive_rate(&self) -> f64 {
+        let k = NUM_HASHES as f64;
+        (1.0 - (-k * self.inserted as f64 / self.num_bits as f64).exp()).powf(k)
+    }
+
+    pub fn len(&self) -> u64 {
+        self.inserted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn contains_exactly_what_was_inserted() {
+        let mut seen = SeenScripts::new(1);
+        let first = script_fingerprint(b"\x00\x14first");
+        let second = script_fingerprint(b"\x00\x14second");
+
+        assert!(!seen.check_and_insert(first));
+        assert!(seen.contains(first));
+        assert!(!seen.contains(second));
+        assert!(seen.check_and_insert(first));
+        assert_eq!(seen.len(), 1);
+    }
+}
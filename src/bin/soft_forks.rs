@@ -0,0 +1,102 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use anyhow::{Result, anyhow};
+use bitcoin::Network;
+
+// BIP9 reserves the top three version bits (001) and leaves 29 bits for signaling
+pub const VERSION_BITS_COUNT: usize = 29;
+const VERSION_BITS_TOP_MASK: i32 = 0xe0000000u32 as i32;
+const VERSION_BITS_TOP_BITS: i32 = 0x20000000;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Deployment {
+    pub name: String,
+    pub bit: u8,
+    pub start_height: u32,   // first height of the first signaling period
+    pub timeout_height: u32, // first height after signaling ended
+}
+
+impl Deployment {
+    pub fn is_active_window(&self, height: u32) -> bool {
+        height >= self.start_height && height < self.timeout_height
+    }
+}
+
+// Whether a header version uses BIP9 semantics and sets the given bit
+pub fn signals_bit(version: i32, bit: u8) -> bool {
+    (bit as usize) < VERSION_BITS_COUNT
+        && version & VERSION_BITS_TOP_MASK == VERSION_BITS_TOP_BITS
+        && version & (1 << bit) != 0
+}
+
+// Built-in deployment tables. Heights are the retarget period boundaries at which
+// each deployment's signaling window opened and closed.
+pub fn default_deployments(network: Network) -> Vec<Deployment> {
+    let deployment = |name: &str, bit, start_height, timeout_height| Deployment {
+        name: name.to_string(),
+        bit,
+        start_height,
+        timeout_height,
+    };
+
+    match network {
+        Network::Bitcoin => vec![
+            deployment("csv", 0, 411_264, 463_680),
+            deployment("segwit", 1, 439_488, 493_920),
+            deployment("taproot", 2, 681_408, 695_520),
+        ],
+        _ => Vec::new(),
+    }
+}
+
+// Load deployment tables from a JSON file of the form
+// {"bitcoin": [{"name": "segwit", "bit": 1, "start_height": 439488, "timeout_height": 493920}], ...}
+// and pick the table for the given network
+pub fn load_deployments<P: AsRef<Path>>(path: P, network: Network) -> Result<Vec<Deployment>> {
+    let file = File::open(&path)?;
+    let tables: HashMap<String, Vec<Deployment>> = serde_json::from_reader(BufReader::new(file))?;
+
+    let deployments = tables.get(&network.to_string())
+        .cloned()
+        .ok_or_else(|| anyhow!("No deployments for network '{}' in {}", network, path.as_ref().display()))?;
+
+    for deployment in &deployments {
+        if deployment.bit as usize >= VERSION_BITS_COUNT {
+            return Err(anyhow!("Deployment '{}' uses bit {}, but only bits 0-{} are available",
+                               deployment.name, deployment.bit, VERSION_BITS_COUNT - 1));
+        }
+    }
+
+    Ok(deployments)
+}
+
+// Per-bit signaling counts for the retarget period containing the most recent block
+pub struct SignalCounter {
+    counts: [u32; VERSION_BITS_COUNT],
+}
+
+impl SignalCounter {
+    pub fn new() -> Self {
+        SignalCounter {
+            counts: [0; VERSION_BITS_COUNT],
+        }
+    }
+
+    pub fn record(&mut self, height: u32, version: i32, period: u32) {
+        if height.is_multiple_of(period) {
+            self.counts = [0; VERSION_BITS_COUNT];
+        }
+        for (bit, count) in self.counts.iter_mut().enumerate() {
+            if signals_bit(version, bit as u8) {
+                *count += 1;
+            }
+        }
+    }
+
+    pub fn count(&self, bit: u8) -> u32 {
+        self.counts.get(bit as usize).copied().unwrap_or(0)
+    }
+}
@@ -0,0 +1,173 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use anyhow::{Result, anyhow};
+use bitcoin::{BlockHash, Txid};
+use bitcoin::hashes::Hash;
+use memmap2::Mmap;
+use crate::bucket_sort::{self, BucketSorter, FANOUT_SIZE};
+
+// On-disk layout (all integers little-endian):
+//
+//   header:  magic "TXIX" | version u32 | tip_height u32 | tip_hash [u8; 32] | entry_count u64
+//   fanout:  257 x u64, entries fanout[b]..fanout[b + 1] have txids starting with byte b
+//   entries: entry_count x ENTRY_SIZE, sorted by txid prefix
+//
+// Each entry is txid prefix [u8; 8] | height u32 | position u32 | tx_offset u32, where
+// position is the transaction's index within its block and tx_offset its byte offset
+// within the serialized block. Prefixes can collide, so lookups return every candidate
+// and callers confirm the txid of the transaction they read.
+const TXINDEX_MAGIC: [u8; 4] = *b"TXIX";
+const TXINDEX_VERSION: u32 = 1;
+const HEADER_SIZE: usize = 52;
+const ENTRY_SIZE: usize = 20;
+const PREFIX_SIZE: usize = 8;
+
+#[derive(Debug, Clone, Copy)]
+pub struct TxPosition {
+    pub height: u32,
+    pub position: u32,
+    pub tx_offset: u32,
+}
+
+pub struct TxIndexBuilder {
+    sorter: BucketSorter<ENTRY_SIZE>,
+}
+
+impl TxIndexBuilder {
+    pub fn new(path: &Path) -> Result<Self> {
+        Ok(TxIndexBuilder {
+            sorter: BucketSorter::new(path.with_extension("idx.buckets"))?,
+        })
+    }
+
+    pub fn add(&mut self, txid: &Txid, position: TxPosition) -> Result<()> {
+        let mut entry = [0u8; ENTRY_SIZE];
+        entry[0..8].copy_from_slice(&txid.as_byte_array()[0..PREFIX_SIZE]);
+        entry[8..12].copy_from_slice(&position.height.to_le_bytes());
+        entry[12..16].copy_from_slice(&position.position.to_le_bytes());
+        entry[16..20].copy_from_slice(&position.tx_offset.to_le_bytes());
+        self.sorter.add(&entry)
+    }
+
+    pub fn len(&self) -> u64 {
+        self.sorter.len()
+    }
+
+    pub fn finish(self, path: &Path, tip_height: u32, tip_hash: BlockHash) -> Result<()> {
+        let temp_path = path.with_extension("idx.tmp");
+        let mut writer = BufWriter::new(File::create(&temp_path)?);
+
+        writer.write_all(&TXINDEX_MAGIC)?;
+        writer.write_all(&TXINDEX_VERSION.to_le_bytes())?;
+        writer.write_all(&tip_height.to_le_bytes())?;
+        writer.write_all(tip_hash.as_byte_array())?;
+        writer.write_all(&self.sorter.len().to_le_bytes())?;
+        self.sorter.finish(&mut writer)?;
+
+        writer.into_inner()?.sync_all()?;
+        fs::rename(&temp_path, path)?;
+        Ok(())
+    }
+}
+
+// Memory-mapped, read-only view of a saved transaction index
+pub struct TxIndex {
+    mmap: Mmap,
+    pub tip_height: u32,
+    pub tip_hash: BlockHash,
+}
+
+impl TxIndex {
+    pub fn load_from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path)?;
+        // SAFETY: the transaction index is only ever replaced by rename, never modified in place
+        let mmap = unsafe { Mmap::map(&file)? };
+
+        if mmap.len() < HEADER_SIZE + FANOUT_SIZE || mmap[0..4] != TXINDEX_MAGIC {
+            return Err(anyhow!("{} is not a transaction index. Please rebuild it with build-txindex.", path.display()));
+        }
+
+        let version = u32::from_le_bytes(mmap[4..8].try_into().unwrap());
+        if version != TXINDEX_VERSION {
+            return Err(anyhow!("Transaction index {} has format version {}, but this build uses version {}. \
+                                Please rebuild it with build-txindex.",
+                               path.display(), version, TXINDEX_VERSION));
+        }
+
+        let tip_height = u32::from_le_bytes(mmap[8..12].try_into().unwrap());
+        let tip_hash = BlockHash::from_byte_array(mmap[12..44].try_into().unwrap());
+        let entry_count = u64::from_le_bytes(mmap[44..52].try_into().unwrap());
+
+        let expected_len = HEADER_SIZE + FANOUT_SIZE + entry_count as usize * ENTRY_SIZE;
+        if mmap.len() != expected_len {
+            return Err(anyhow!("Transaction index {} has {} bytes, expected {} for {} entries",
+                               path.display(), mmap.len(), expected_len, entry_count));
+        }
+
+        Ok(TxIndex {
+            mmap,
+            tip_height,
+            tip_hash,
+        })
+    }
+
+    // Positions of every transaction whose txid shares its prefix with the given one
+    pub fn candidates(&self, txid: &Txid) -> Vec<TxPosition> {
+        let prefix = &txid.as_byte_array()[0..PREFIX_SIZE];
+        bucket_sort::find_prefix::<ENTRY_SIZE>(&self.mmap[HEADER_SIZE..], prefix)
+            .chunks_exact(ENTRY_SIZE)
+            .map(|entry| TxPosition {
+                height: u32::from_le_bytes(entry[8..12].try_into().unwrap()),
+                position: u32::from_le_bytes(entry[12..16].try_into().unwrap()),
+                tx_offset: u32::from_le_bytes(entry[16..20].try_into().unwrap()),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn txid(prefix: [u8; PREFIX_SIZE], rest: u8) -> Txid {
+        let mut bytes = [rest; 32];
+        bytes[0..PREFIX_SIZE].copy_from_slice(&prefix);
+        Txid::from_byte_array(bytes)
+    }
+
+    #[test]
+    fn returns_every_candidate_sharing_a_prefix() {
+        let path = std::env::temp_dir().join(format!("txindex_test_{}.idx", std::process::id()));
+        let shared = [0xab; PREFIX_SIZE];
+        let first = txid(shared, 1);
+        let second = txid(shared, 2);
+        let other = txid([0x01; PREFIX_SIZE], 3);
+
+        let mut builder = TxIndexBuilder::new(&path).unwrap();
+        builder.add(&second, TxPosition { height: 7, position: 3, tx_offset: 900 }).unwrap();
+        builder.add(&other, TxPosition { height: 5, position: 0, tx_offset: 81 }).unwrap();
+        builder.add(&first, TxPosition { height: 6, position: 1, tx_offset: 250 }).unwrap();
+        assert_eq!(builder.len(), 3);
+        let tip_hash = BlockHash::from_byte_array([9; 32]);
+        builder.finish(&path, 7, tip_hash).unwrap();
+
+        let index = TxIndex::load_from_file(&path).unwrap();
+        assert_eq!((index.tip_height, index.tip_hash), (7, tip_hash));
+
+        let mut candidates: Vec<(u32, u32, u32)> = index.candidates(&first).iter()
+            .map(|candidate| (candidate.height, candidate.position, candidate.tx_offset))
+            .collect();
+        candidates.sort_unstable();
+        assert_eq!(candidates, vec![(6, 1, 250), (7, 3, 900)]);
+
+        let found = index.candidates(&other);
+        assert_eq!(found.len(), 1);
+        assert_eq!((found[0].height, found[0].position, found[0].tx_offset), (5, 0, 81));
+
+        assert!(index.candidates(&txid([0xac; PREFIX_SIZE], 0)).is_empty());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
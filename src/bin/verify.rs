@@ -0,0 +1,184 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU32, Ordering};
+use anyhow::Result;
+use bitcoin::BlockHash;
+use bitcoin::hashes::Hash;
+use crate::block_parser::{BlockFileReader, BlockReadErrorKind, MappedBlockReader};
+use crate::index::{BlockIndex, BlockIndexBuilder, BlockLocation};
+
+#[derive(Debug, Clone)]
+pub struct IndexProblem {
+    pub height: u32,
+    pub file_path: Option<String>, // None when the index has no entry at this height
+    pub description: String,
+    pub repairable: bool, // whether finding the block elsewhere in its file could fix it
+}
+
+// Check that a location holds the expected block: magic and size, header hash, stored
+// summary, linkage to the previous block (when its entry exists) and, optionally, the
+// merkle root and witness commitment
+fn verify_location(
+    reader: &mut MappedBlockReader,
+    height: u32,
+    location: &BlockLocation,
+    expected_prev: Option<BlockHash>,
+    check_merkle: bool,
+) -> std::result::Result<(), (String, bool)> {
+    let header = if check_merkle {
+        reader.read_block_at(height, location, true).map(|block| block.header)
+    } else {
+        reader.read_header_at(height, location)
+    };
+
+    // Only a block missing from its indexed offset can be fixed by finding it elsewhere in the file
+    let header = header.map_err(|e| match e.kind {
+        BlockReadErrorKind::Unreadable(reason) => (format!("cannot read block: {}", reason), true),
+        BlockReadErrorKind::Missing => ("no block found at the indexed offset".to_string(), true),
+        BlockReadErrorKind::SizeMismatch { found, expected } => (format!("block size {} differs from indexed size {}", found, expected), true),
+        BlockReadErrorKind::HashMismatch { found } => (format!("found block {} instead of {}", found, location.block_hash), true),
+        BlockReadErrorKind::MerkleRootMismatch => ("merkle root does not match the transactions (block data is corrupt)".to_string(), false),
+        BlockReadErrorKind::WitnessCommitmentMismatch => ("witness commitment does not match the transactions (block data is corrupt)".to_string(), false),
+    })?;
+
+    if let Some(summary) = &location.summary {
+        if summary.header != header {
+            return Err(("stored header differs from the block file".to_string(), true));
+        }
+    }
+    if let Some(expected_prev) = expected_prev {
+        if header.prev_blockhash != expected_prev {
+            return Err((format!("previous block hash {} does not match indexed parent {}", header.prev_blockhash, expected_prev), false));
+        }
+    }
+
+    Ok(())
+}
+
+// Verify every height in the index, splitting the chain into contiguous ranges checked in parallel
+pub fn verify_index(block_index: &BlockIndex, xor_key: [u8; 8], check_merkle: bool, threads: usize) -> Vec<IndexProblem> {
+    let tip_height = block_index.tip_height;
+    let threads = threads.max(1) as u32;
+    let chunk_size = (tip_height / threads + 1).max(1);
+    let problems = Mutex::new(Vec::new());
+    let verified = AtomicU32::new(0);
+
+    std::thread::scope(|scope| {
+        for start in (0..=tip_height).step_by(chunk_size as usize) {
+            let end = start.saturating_add(chunk_size - 1).min(tip_height);
+            let problems = &problems;
+            let verified = &verified;
+
+            scope.spawn(move || {
+                let mut found = Vec::new();
+                let mut reader = MappedBlockReader::new(xor_key);
+                // The block file most recently confirmed to exist
+                let mut present_file: Option<String> = None;
+
+                for height in start..=end {
+                    let Some(location) = block_index.get_block_location(height) else {
+                        found.push(IndexProblem {
+                            height,
+                            file_path: None,
+                            description: "no entry in the index".to_string(),
+                            repairable: false,
+                        });
+                        continue;
+                    };
+
+                    // Without the parent's entry there is nothing to check the linkage against;
+                    // the missing entry is reported at its own height
+                    let expected_prev = match height {
+                        0 => Some(BlockHash::all_zeros()),
+                        _ => block_index.get_block_location(height - 1).map(|parent| parent.block_hash),
+                    };
+
+                    if present_file.as_deref() != Some(location.file_path.as_str()) {
+                        if let Err(e) = std::fs::metadata(&location.file_path) {
+                            found.push(IndexProblem {
+                                height,
+                                file_path: Some(location.file_path.clone()),
+                                description: format!("cannot open block file: {}", e),
+                                repairable: false,
+                            });
+                            continue;
+                        }
+                        present_file = Some(location.file_path.clone());
+                    }
+
+                    if let Err((description, repairable)) = verify_location(&mut reader, height, &location, expected_prev, check_merkle) {
+                        found.push(IndexProblem {
+                            height,
+                            file_path: Some(location.file_path.clone()),
+                            description,
+                            repairable,
+                        });
+                    }
+
+                    let count = verified.fetch_add(1, Ordering::Relaxed) + 1;
+                    if count.is_multiple_of(10000) {
+                        println!("Verified {} of {} blocks", count, tip_height + 1);
+                    }
+                }
+
+                problems.lock().unwrap().extend(found);
+            });
+        }
+    });
+
+    let mut problems = problems.into_inner().unwrap();
+    problems.sort_by_key(|problem| problem.height);
+    problems
+}
+
+// Rescan the block files named by repairable problems and point each affected height at
+// wherever its block now lives. Heights that cannot be fixed are left out of the rewritten
+// index rather than keeping entries known to be wrong. Returns the rewritten index and those heights.
+pub fn repair_index(block_index: &BlockIndex, problems: &[IndexProblem], xor_key: [u8; 8]) -> Result<(BlockIndexBuilder, Vec<u32>)> {
+    let affected_files: BTreeSet<&str> = problems.iter()
+        .filter(|problem| problem.repairable)
+        .filter_map(|problem| problem.file_path.as_deref())
+        .collect();
+
+    let mut found: HashMap<BlockHash, BlockLocation> = HashMap::new();
+    for file_path in affected_files {
+        println!("Rescanning {}", file_path);
+        let mut reader = BlockFileReader::new_with_xor_key(file_path, xor_key)?;
+        // Damaged data in the file still leaves the blocks around it usable
+        let mut skipped = Vec::new();
+        while let Some((summary, offset, block_size)) = reader.read_next_summary_recovering(&mut skipped)? {
+            let block_hash = summary.header.block_hash();
+            found.insert(block_hash, BlockLocation {
+                file_path: file_path.to_string(),
+                file_offset: offset,
+                block_hash,
+                block_size,
+                summary: Some(summary),
+            });
+        }
+        for range in &skipped {
+            println!("  Skipped bytes {}..{}: {}", range.start, range.end, range.reason);
+        }
+    }
+
+    let broken: BTreeSet<u32> = problems.iter().map(|problem| problem.height).collect();
+    let mut builder = BlockIndexBuilder::new(block_index.metadata);
+    for (height, location) in block_index.iter_reverse() {
+        if !broken.contains(&height) {
+            builder.add_block(height, location);
+        }
+    }
+
+    let mut unrepaired = Vec::new();
+    for problem in problems {
+        let relocated = block_index.get_block_location(problem.height)
+            .filter(|_| problem.repairable)
+            .and_then(|location| found.get(&location.block_hash));
+        match relocated {
+            Some(location) => builder.add_block(problem.height, location.clone()),
+            None => unrepaired.push(problem.height),
+        }
+    }
+
+    Ok((builder, unrepaired))
+}